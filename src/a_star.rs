@@ -1,327 +1,1033 @@
-use std::{
-    collections::{HashMap, LinkedList, BTreeMap},
-    ops::Index,
-};
+use las::{Point, Vector};
 
-use las::Point;
-
-use crate::model::Comparison;
-use crate::model::Octree;
+use crate::ground::GroundMap;
+use crate::model::{Adjacency, NodeId, Octree};
+use crate::occupancy::OccupancyMap;
+use crate::search::{construct_path, flood, Algorithm, Frontier, SearchProblem};
 
+//A search node, parent is its index in the list of nodes the search created and g the
+//cost of the actions from the start to it.
 #[derive(Clone, Debug)]
 pub struct Node {
     pub state: State,
-    pub parent: Option<Box<Node>>,
+    pub parent: Option<usize>,
     pub action: Option<Action>,
+    pub g: f64,
 }
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct State {
-    pub start: Box<Octree>,
-    pub tree: Box<Octree>,
+    pub start: NodeId,
 }
 
 pub struct ActionStatePair {
     pub action: Action,
     pub state: State,
 }
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct Action {
-    pub move_to: Box<Octree>,
-    pub move_from: Box<Octree>,
-    pub cost: i32,
+    pub move_to: NodeId,
+    pub move_from: NodeId,
+    pub cost: f64,
 }
+//Nodes from the goal back to the start, total_cost is the sum of their action costs.
 #[derive(Debug)]
 pub struct Path {
-    pub total_cost: i32,
+    pub total_cost: f64,
     pub nodes: Vec<Node>,
 }
 
-pub struct Problem {
-    pub nodes_visited: i32,
-    pub start_state: State,
-    pub goal_state: State,
+//Path found by searching from both ends, with the nodes expanded on each side.
+#[derive(Debug)]
+pub struct Bidirectional {
+    pub path: Option<Path>,
+    pub forward_expanded: usize,
+    pub backward_expanded: usize,
 }
 
-impl Node {
-    pub fn get_cost(&self) -> i32 {
-        let mut result = 0;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    //moves between a node and its parent or children, every move costs 1
+    Hierarchy,
+    //moves between leaf cells that touch, a move costs the distance between cell centres
+    Cells,
+}
 
-        let mut current_node = self.clone();
+pub struct Problem<'a> {
+    pub tree: &'a Octree,
+    pub mode: Mode,
+    //when set, only free cells that are not blocked are entered, apart from the goal
+    pub occupancy: Option<&'a OccupancyMap>,
+    //when set, cell moves walk over the ground surface instead of through space
+    pub ground: Option<&'a GroundMap>,
+    //put closed nodes back on the fringe when a cheaper way to them turns up, only needed
+    //when the heuristic is not consistent
+    pub reopen: bool,
+    pub nodes_visited: i32,
+    pub start_state: State,
+    //the search ends at any of the goals, or at any state goal_test accepts when it is set
+    pub goals: Vec<State>,
+    pub goal_test: Option<&'a dyn Fn(State) -> bool>,
+}
 
-        while let Some(parent) = current_node.parent.clone() {
-            if let Some(action) = parent.action.clone() {
-                result += action.clone().cost; 
-            }
-            
-            current_node = *parent;
+impl<'a> Problem<'a> {
+    pub fn new(tree: &'a Octree, mode: Mode, initial_state: State, goal_state: State) -> Self {
+        Problem {
+            tree,
+            mode,
+            occupancy: None,
+            ground: None,
+            reopen: true,
+            nodes_visited: 0,
+            start_state: initial_state,
+            goals: vec![goal_state],
+            goal_test: None,
         }
-        result
     }
-}
 
-impl Problem {
+    //Searches for the nearest of the goals instead.
+    pub fn with_goals(mut self, goals: Vec<State>) -> Self {
+        self.goals = goals;
+        self
+    }
 
+    pub fn with_goal_test(mut self, goal_test: &'a dyn Fn(State) -> bool) -> Self {
+        self.goal_test = Some(goal_test);
+        self
+    }
 
-    pub fn new(initial_state:State, goal_state:State) -> Self {
-        Problem { nodes_visited: 0, start_state: initial_state, goal_state: goal_state }
+    pub fn with_occupancy(mut self, occupancy: &'a OccupancyMap) -> Self {
+        self.occupancy = Some(occupancy);
+        self
     }
 
-    pub fn is_goal(&self, state: State) -> bool {
-        state.equals(self.goal_state.clone())
+    pub fn with_reopening(mut self, reopen: bool) -> Self {
+        self.reopen = reopen;
+        self
     }
 
-    pub fn construct_path(mut node: Node) -> Path {
-        let mut result = Path {
-            total_cost: 0,
-            nodes: Vec::new(),
-        };
+    pub fn with_ground(mut self, ground: &'a GroundMap) -> Self {
+        self.ground = Some(ground);
+        self
+    }
 
-        result.total_cost += node.get_cost();
-        result.nodes.push(node.clone());
+    //Where the path passes through a cell: its centre, or its ground surface when walking.
+    pub fn position(&self, id: NodeId) -> Vector<f64> {
+        self.ground
+            .and_then(|ground| ground.surface(id))
+            .unwrap_or_else(|| self.tree[id].centre())
+    }
 
-        while let Some(parent) = node.parent.clone() {
-            result.total_cost += parent.get_cost();
-            result.nodes.push(*parent.clone());
-            node = *parent.clone();
-        }
+    //Route from a to b through the positions of the cells on the path.
+    pub fn waypoints(&self, path: &Path, from: Vector<f64>, to: Vector<f64>) -> Vec<Vector<f64>> {
+        let mut result = vec![from];
+        result.extend(
+            path.nodes
+                .iter()
+                .rev()
+                .map(|node| self.position(node.state.start)),
+        );
+        result.push(to);
         result
     }
 
-    pub fn add_child_binary(
-        &self,
-        fringe: &mut Vec<Node>,
-        node: Node,
-        mut left: usize,
-        right: &mut usize,
-    ) {
-        while (true) {
-            if left > *right {
-                fringe.insert(left.try_into().unwrap(), node);
-                return;
-            }
-            let node_value = self.evaluation(node.clone());
-            if left == *right {
-                let left_value = self.evaluation(fringe[left].clone());
-                if left_value > node_value {
-                    fringe.insert(left, node);
-                    return;
-                }
-                if left_value == node_value {
-                    if fringe[left].get_cost() > node.get_cost() {
-                        fringe.insert(left + 1, node);
-                        return;
-                    }
-                    fringe.insert(left, node);
-                    return;
-                }
-                fringe.insert(left + 1, node);
-                return;
-            }
-            let mid = (left + *right) / 2;
-            
-            let mut mid_value = 0;
+    fn can_enter(&self, state: State) -> bool {
+        self.is_goal(state) || self.is_open(state.start)
+    }
 
-            if let Some(node) = fringe.get(mid){
-                mid_value = self.evaluation(node.clone());
-            }
-            if mid_value == node_value {
-                if fringe[mid].get_cost() > node.get_cost() {
-                    fringe.insert(mid + 1, node);
-                    return;
-                }
-                fringe.insert(mid, node);
-                return;
-            }
-            if mid_value > node_value {
-                *right = mid - 1;
-            } else {
-                left = mid + 1;
-            }
-        }
+    //Whether a path may pass through the cell: it is free when occupancy is known and has
+    //ground to stand on when walking.
+    pub fn is_open(&self, id: NodeId) -> bool {
+        self.occupancy
+            .is_none_or(|occupancy| occupancy.is_traversable(id))
+            && self
+                .ground
+                .is_none_or(|ground| ground.surface(id).is_some())
     }
 
-    pub fn evaluation(&self, node: Node) -> i32 {
-        node.get_cost() + self.heuristic(node.state)
+    pub fn evaluation(&self, node: &Node) -> f64 {
+        node.g + self.heuristic(node.state)
     }
 
-    pub fn heuristic(&self, current_state: State) -> i32 {
-        if current_state.start.depth > self.goal_state.start.depth {
-            if self
-                .goal_state
-                .start
-                .bounds
-                .contains_area(current_state.start.bounds)
-            {
-                current_state.start.depth - self.goal_state.start.depth
+    fn depth_heuristic(&self, current_state: State, goal_state: State) -> i32 {
+        let current = &self.tree[current_state.start];
+        let goal = &self.tree[goal_state.start];
+
+        if current.depth > goal.depth {
+            if self.tree.is_ancestor(goal_state.start, current_state.start) {
+                current.depth - goal.depth
             } else {
-                (current_state.start.depth - self.goal_state.start.depth) + 1
+                (current.depth - goal.depth) + 1
             }
-        } else if current_state.start.depth < self.goal_state.start.depth {
-            if current_state
-                .start
-                .bounds
-                .contains_area(self.goal_state.start.bounds)
-            {
-                self.goal_state.start.depth - current_state.start.depth
+        } else if current.depth < goal.depth {
+            if self.tree.is_ancestor(current_state.start, goal_state.start) {
+                goal.depth - current.depth
             } else {
-                (self.goal_state.start.depth - current_state.start.depth) + 1
+                (goal.depth - current.depth) + 1
             }
         } else {
-            match current_state.tree.find_parent(&current_state.start) {
-                Some(current_state_parent) => {
-                    match self.goal_state.tree.find_parent(&self.goal_state.start) {
-                        Some(goal_state_parent) => {
-                            if current_state_parent.bounds == goal_state_parent.bounds {
-                                current_state_parent.depth - current_state.start.depth
-                            } else {
-                                current_state_parent.depth - current_state.start.depth + 2
-                            }
+            match self.tree.find_parent(current_state.start) {
+                Some(current_state_parent) => match self.tree.find_parent(goal_state.start) {
+                    Some(goal_state_parent) => {
+                        let parent_depth = self.tree[current_state_parent].depth;
+                        if current_state_parent == goal_state_parent {
+                            parent_depth - current.depth
+                        } else {
+                            parent_depth - current.depth + 2
                         }
-                        None => 100000000,
                     }
-                }
+                    None => 100000000,
+                },
                 None => 100000000,
             }
         }
     }
 
-    pub fn search(&mut self) -> Option<Path> {
-        //NOTE: originally I wanted to use a hash map for this
-        //however, it does not work without trait implementations for structs in the LAS library
-        //that I can not modify without modifying the library itself.
-        let mut visited_nodes = HashMap::new();
+    //Estimate of the cost from one state to the other.
+    fn estimate(&self, from: State, to: State) -> f64 {
+        match self.mode {
+            Mode::Hierarchy => self.depth_heuristic(from, to) as f64,
+            //straight line between the centres, no chain of moves can be shorter
+            Mode::Cells => distance(&self.position(from.start), &self.position(to.start)),
+        }
+    }
 
-        
+    //Every move out of the state, whether or not the cell it leads to can be entered.
+    pub(crate) fn moves(&self, state: State) -> Vec<ActionStatePair> {
+        match (self.mode, self.ground) {
+            (Mode::Cells, Some(ground)) => ground
+                .successors(self.tree, state.start)
+                .into_iter()
+                .map(|(neighbour, cost)| ActionStatePair {
+                    action: Action {
+                        move_to: neighbour,
+                        move_from: state.start,
+                        cost,
+                    },
+                    state: State { start: neighbour },
+                })
+                .collect(),
+            _ => state.successor(self.tree, self.mode),
+        }
+    }
 
-        let mut fringe = Vec::new();
+    //Moves that lead into the state, for searching back from the goal. Every kind of move
+    //can be taken both ways at the same cost, so these are the moves out of the state turned
+    //around, from the cells a path could have come through.
+    fn predecessors(&self, state: State) -> Vec<ActionStatePair> {
+        self.moves(state)
+            .into_iter()
+            .filter(|parent| parent.state == self.start_state || self.can_enter(parent.state))
+            .map(|parent| ActionStatePair {
+                action: Action {
+                    move_to: state.start,
+                    move_from: parent.state.start,
+                    cost: parent.action.cost,
+                },
+                state: parent.state,
+            })
+            .collect()
+    }
+
+    pub fn search(&mut self) -> Option<Path> {
+        self.search_with(Algorithm::AStar)
+    }
 
-        let root_node = Node {
-            state: self.start_state.clone(),
-            parent: None,
-            action: None,
+    //Runs any of the algorithms on the problem, the nodes it generated are added to
+    //nodes_visited.
+    pub fn search_with(&mut self, algorithm: Algorithm) -> Option<Path> {
+        let search = algorithm.run(&*self, self.reopen);
+        self.nodes_visited += search.generated as i32;
+        search.path
+    }
+
+    //Cheapest cost from the start to every cell it can reach, by NodeId. The goals are left
+    //out, so a closed goal cell is not passed through.
+    pub fn cost_map(&self) -> Vec<Option<f64>> {
+        let open = Problem {
+            goals: Vec::new(),
+            goal_test: None,
+            ..*self
         };
-        fringe.push(root_node.clone());
-        visited_nodes.insert(root_node.clone().state, root_node);
-        
+        let mut costs = vec![None; self.tree.nodes.len()];
+        for node in flood(&open) {
+            costs[node.state.start.0] = Some(node.g);
+        }
+        costs
+    }
 
-        self.nodes_visited += 1;
+    //A* from the start and back from the goal at once, each side expanding in turn while its
+    //fringe is the smaller one. Every path found where the two sides meet is an upper bound
+    //on the cost, and a path not found yet costs at least the lowest f on either fringe, so
+    //the search stops once the cheapest meeting is no more than the larger of the two.
+    //With several goals the backward side starts from all of them. A goal test cannot be
+    //searched back from, so then only the forward side runs.
+    pub fn search_bidirectional(&mut self) -> Bidirectional {
+        if self.goal_test.is_some() {
+            let search = Algorithm::AStar.run(&*self, self.reopen);
+            self.nodes_visited += search.generated as i32;
+            return Bidirectional {
+                path: search.path,
+                forward_expanded: search.expanded,
+                backward_expanded: 0,
+            };
+        }
 
-        if self.nodes_visited % 1000 == 0 {
-            println!("Explored {} Nodes", self.nodes_visited);
+        let start = self.start_state;
+        let forward_f = |node: &Node| node.g + self.heuristic(node.state);
+        let backward_f = |node: &Node| node.g + self.estimate(node.state, start);
+        let mut forward = Frontier::new(start, self.heuristic(start));
+        let mut backward = Frontier::default();
+        for goal in &self.goals {
+            backward.add_start(*goal, self.estimate(*goal, start));
         }
 
-        while true {
-            println!("Nodes visited: {}", self.nodes_visited);
-            if fringe.is_empty() {
-                
-                return None;
+        //cost of the cheapest path through a state both sides have reached
+        let mut best = self.goals.contains(&start).then_some((0.0, start));
+
+        while let (Some(forward_min), Some(backward_min)) = (forward.min_f(), backward.min_f()) {
+            if best.is_some_and(|(cost, _)| cost <= forward_min.max(backward_min)) {
+                break;
             }
-            let node = fringe.remove(0);
 
-            println!("node depth: {}", node.state.start.depth);
+            let forward_turn = forward.open() <= backward.open();
+            let (side, other) = if forward_turn {
+                (&mut forward, &backward)
+            } else {
+                (&mut backward, &forward)
+            };
+            let Some(index) = side.pop() else {
+                break;
+            };
+            side.expanded += 1;
 
-            if self.is_goal(node.state.clone()) {
-                println!("goal found");
-                return Some(Problem::construct_path(node.clone()));
+            let state = side.nodes[index].state;
+            let children = if forward_turn {
+                self.successors(state)
+            } else {
+                self.predecessors(state)
+            };
+            for child in children {
+                let reached = if forward_turn {
+                    side.relax(index, child, forward_f, self.reopen)
+                } else {
+                    side.relax(index, child, backward_f, self.reopen)
+                };
+                let Some(reached) = reached else {
+                    continue;
+                };
+                let meeting = side.nodes[reached].state;
+                if let Some(other_index) = other.index(meeting) {
+                    let cost = side.nodes[reached].g + other.nodes[other_index].g;
+                    if best.is_none_or(|(best_cost, _)| cost < best_cost) {
+                        best = Some((cost, meeting));
+                    }
+                }
             }
+        }
 
-            let child_nodes = node.state.successor();
-            for child in child_nodes {
-                self.nodes_visited += 1;
-                if self.nodes_visited % 1000 == 0 {
-                    println!("Explored {} Nodes", self.nodes_visited);
-                }
-                let last_seen_node = visited_nodes.get_mut(&child.state);
+        self.nodes_visited += (forward.generated + backward.generated) as i32;
+        Bidirectional {
+            path: best.map(|(_, meeting)| join(&forward, &backward, meeting)),
+            forward_expanded: forward.expanded,
+            backward_expanded: backward.expanded,
+        }
+    }
+}
 
-                match last_seen_node {
-                    Some(last_seen_node) => {
-                        if last_seen_node.get_cost()>child.action.cost + node.get_cost(){
-                            last_seen_node.parent = Some(Box::new(node.clone()));
-                            last_seen_node.action = Some(child.action.clone());
-                        }
-                    }
-                    None => {
-                        let child_node = Node {
-                            state: child.state,
-                            parent: Some(Box::new(node.clone())),
-                            action: Some(child.action),
-                        };
+//Path from the start of the forward side to the meeting state, carried on to the goal
+//along the parents of the backward side.
+fn join(forward: &Frontier, backward: &Frontier, meeting: State) -> Path {
+    let mut chain = Vec::new();
+    let mut index = forward.index(meeting);
+    while let Some(current) = index {
+        chain.push(current);
+        index = forward.nodes[current].parent;
+    }
 
-                        let mut right = fringe.len() - 1;
+    let mut nodes: Vec<Node> = chain
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(position, current)| Node {
+            parent: position.checked_sub(1),
+            ..forward.nodes[current].clone()
+        })
+        .collect();
 
-                        self.add_child_binary(&mut fringe, child_node, 0, &mut right);
-                    },
-                }
-                
-            }
+    //the backward side stores on every node the move from it to its parent
+    let mut index = backward.index(meeting);
+    while let Some(current) = index {
+        let node = &backward.nodes[current];
+        if let (Some(parent), Some(action)) = (node.parent, node.action) {
+            nodes.push(Node {
+                state: backward.nodes[parent].state,
+                parent: Some(nodes.len() - 1),
+                action: Some(action),
+                g: nodes[nodes.len() - 1].g + action.cost,
+            });
         }
-        None
+        index = node.parent;
     }
+
+    construct_path(&nodes, nodes.len() - 1)
 }
 
-impl State {
-    pub fn new(start_point: Point, tree: &Octree) -> Option<Self> {
-        
-        let starting_octant = tree.search_for_octant(&start_point);
-        
-        if let Some(starting_octant) = starting_octant {
-            return Some(State {
-                    start: Box::new(*starting_octant),
-                    tree: Box::new(tree.clone()),
-                });
-        } else {
-            return None
+impl SearchProblem for Problem<'_> {
+    fn start(&self) -> State {
+        self.start_state
+    }
+
+    //Moves into cells that can be entered.
+    fn successors(&self, state: State) -> Vec<ActionStatePair> {
+        self.moves(state)
+            .into_iter()
+            .filter(|child| self.can_enter(child.state))
+            .collect()
+    }
+
+    fn is_goal(&self, state: State) -> bool {
+        self.goals.iter().any(|goal| state.equals(*goal))
+            || self.goal_test.is_some_and(|goal_test| goal_test(state))
+    }
+
+    //The estimate to the nearest goal, which never overestimates when the estimate to each
+    //goal does not. Any state may pass a goal test, so with one nothing above 0 is safe.
+    fn heuristic(&self, current_state: State) -> f64 {
+        if self.goal_test.is_some() {
+            return 0.0;
         }
+        self.goals
+            .iter()
+            .map(|goal| self.estimate(current_state, *goal))
+            .fold(f64::INFINITY, f64::min)
     }
+}
 
-    pub fn successor(&self) -> Vec<ActionStatePair> {
+impl State {
+    pub fn new(start_point: &Point, tree: &Octree) -> Option<Self> {
+        let starting_octant = tree.search_for_octant(start_point);
+
+        starting_octant.map(|starting_octant| State {
+            start: starting_octant,
+        })
+    }
+
+    //State of the leaf cell around the coordinates, which need not be a point of the cloud.
+    pub fn at(x: f64, y: f64, z: f64, tree: &Octree) -> Option<Self> {
+        tree.locate(x, y, z).map(|start| State { start })
+    }
+
+    pub fn successor(&self, tree: &Octree, mode: Mode) -> Vec<ActionStatePair> {
         let mut result = Vec::new();
 
+        if mode == Mode::Cells {
+            let centre = tree[self.start].centre();
+            for neighbour in tree.neighbours(self.start, Adjacency::Vertex) {
+                let action = Action {
+                    move_to: neighbour,
+                    move_from: self.start,
+                    cost: distance(&centre, &tree[neighbour].centre()),
+                };
+                let next_state = State {
+                    start: action.move_to,
+                };
+                result.push(ActionStatePair {
+                    action,
+                    state: next_state,
+                });
+            }
+            return result;
+        }
 
-        
-        if let Some(parent) = self.tree.find_parent(&self.start) {
+        if let Some(parent) = tree.find_parent(self.start) {
             let action = Action {
                 move_to: parent,
-                move_from: self.start.clone(),
-                cost: 1,
+                move_from: self.start,
+                cost: 1.0,
             };
             let next_state = State {
-                start: action.move_to.clone(),
-                tree: self.tree.clone(),
+                start: action.move_to,
             };
             result.push(ActionStatePair {
-                action: action,
+                action,
                 state: next_state,
             });
         }
-        else {
-            println!("None");
+
+        for child in tree[self.start].children.into_iter().flatten() {
+            let action = Action {
+                move_to: child,
+                move_from: self.start,
+                cost: 1.0,
+            };
+            let next_state = State {
+                start: action.move_to,
+            };
+            result.push(ActionStatePair {
+                action,
+                state: next_state,
+            });
         }
 
-        for child in self.start.clone().children {
-            if let Some(child) = child {
-                let action = Action {
-                    move_to: child,
-                    move_from: self.start.clone(),
-                    cost: 1,
-                };
-                let next_state = State {
-                    start: action.move_to.clone(),
-                    tree: self.tree.clone(),
-                };
-                result.push(ActionStatePair {
-                    action: action,
-                    state: next_state,
-                });
+        result
+    }
+
+    pub fn equals(&self, state_to_check: State) -> bool {
+        self.start == state_to_check.start
+    }
+}
+
+pub(crate) fn distance(a: &Vector<f64>, b: &Vector<f64>) -> f64 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::OctreeConfig;
+    use crate::test_util::{point, unit_cells, vector, Rng};
+    use std::collections::{HashMap, HashSet};
+
+    fn point_vector(point: &Point) -> Vector<f64> {
+        Vector {
+            x: point.x,
+            y: point.y,
+            z: point.z,
+        }
+    }
+
+    //A diagonal wall of points from one corner of the cube to the other, every point in
+    //its own leaf.
+    fn diagonal() -> (Octree, Vec<Point>) {
+        let points: Vec<Point> = (0..16)
+            .map(|i| point(i as f64 + 0.5, i as f64 + 0.5, 0.5))
+            .collect();
+        let config = OctreeConfig {
+            max_depth: 5,
+            max_points_per_leaf: 1,
+            min_edge_length: 0.0,
+        };
+        (
+            Octree::from_points(points.clone(), &config).unwrap(),
+            points,
+        )
+    }
+
+    fn solve(problem: &mut Problem, from: Vector<f64>, to: Vector<f64>) -> (f64, Vec<Vector<f64>>) {
+        let path = problem.search().unwrap();
+        let waypoints = problem.waypoints(&path, from, to);
+        (path.total_cost, waypoints[1..waypoints.len() - 1].to_vec())
+    }
+
+    fn random_cells(rng: &mut Rng) -> Octree {
+        let count = 1 + rng.below(60);
+        let points: Vec<Point> = (0..count)
+            .map(|_| point(rng.quarter(32), rng.quarter(32), rng.quarter(32)))
+            .collect();
+        unit_cells(8, points)
+    }
+
+    fn leaves(octree: &Octree) -> Vec<NodeId> {
+        octree
+            .iter_nodes()
+            .filter(|(_, node)| node.is_leaf())
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    fn random_leaf(rng: &mut Rng, leaves: &[NodeId]) -> State {
+        State {
+            start: leaves[rng.below(leaves.len() as u64) as usize],
+        }
+    }
+
+    //Nodes run from the goal back to the start, every action moves out of the node after it
+    //and the costs add up to the total.
+    fn assert_chained(path: &Path, start: State, goal: State) {
+        assert_eq!(path.nodes.first().unwrap().state, goal);
+        assert_eq!(path.nodes.last().unwrap().state, start);
+        for pair in path.nodes.windows(2) {
+            let action = pair[0].action.unwrap();
+            assert_eq!(action.move_to, pair[0].state.start);
+            assert_eq!(action.move_from, pair[1].state.start);
+        }
+        let cost: f64 = path
+            .nodes
+            .iter()
+            .flat_map(|node| node.action)
+            .map(|action| action.cost)
+            .sum();
+        assert!((cost - path.total_cost).abs() < 1e-9);
+    }
+
+    //Plain Dijkstra over the same moves, the cost A* has to match.
+    fn cheapest_cost(problem: &Problem) -> Option<f64> {
+        let mut best = HashMap::from([(problem.start_state, 0.0_f64)]);
+        let mut done = HashSet::new();
+        loop {
+            let (state, g) = best
+                .iter()
+                .filter(|(state, _)| !done.contains(*state))
+                .min_by(|a, b| a.1.total_cmp(b.1))
+                .map(|(state, g)| (*state, *g))?;
+            if problem.is_goal(state) {
+                return Some(g);
+            }
+            done.insert(state);
+            for child in problem.successors(state) {
+                let child_g = g + child.action.cost;
+                if best.get(&child.state).is_none_or(|old| child_g < *old) {
+                    best.insert(child.state, child_g);
+                }
             }
         }
+    }
 
-        
+    #[test]
+    fn cells_mode_walks_through_touching_leaves() {
+        let (octree, points) = diagonal();
+        let (a, b) = (&points[0], &points[15]);
+        let start = State::new(a, &octree).unwrap();
+        let goal = State::new(b, &octree).unwrap();
 
-        result
+        let mut problem = Problem::new(&octree, Mode::Cells, start, goal);
+        let path = problem.search().unwrap();
+
+        let cells: Vec<NodeId> = path
+            .nodes
+            .iter()
+            .rev()
+            .map(|node| node.state.start)
+            .collect();
+        assert_eq!(cells.first(), Some(&start.start));
+        assert_eq!(cells.last(), Some(&goal.start));
+        for pair in cells.windows(2) {
+            assert!(octree
+                .neighbours(pair[0], Adjacency::Vertex)
+                .contains(&pair[1]));
+        }
+
+        let waypoints = problem.waypoints(&path, point_vector(a), point_vector(b));
+        assert_eq!(waypoints.len(), cells.len() + 2);
+        assert_eq!(waypoints[0], point_vector(a));
+        assert_eq!(waypoints[waypoints.len() - 1], point_vector(b));
     }
 
-    pub fn equals(&self, state_to_check: State) -> bool {
-        self.start.equals(&state_to_check.start) && self.tree.equals(&state_to_check.tree)
+    #[test]
+    fn cells_mode_costs_are_distances_between_centres() {
+        let (octree, points) = diagonal();
+        let start = State::new(&points[3], &octree).unwrap();
+
+        for pair in start.successor(&octree, Mode::Cells) {
+            let from = octree[pair.action.move_from].centre();
+            let to = octree[pair.action.move_to].centre();
+            assert_eq!(pair.action.cost, distance(&from, &to));
+            assert_eq!(pair.state.start, pair.action.move_to);
+        }
+    }
+
+    #[test]
+    fn free_cells_lead_through_the_gap_in_a_wall() {
+        use crate::occupancy::{OccupancyConfig, OccupancyMap};
+
+        let wall = (0..64)
+            .filter(|i| *i != 63)
+            .map(|i| point(4.5, (i / 8) as f64 + 0.5, (i % 8) as f64 + 0.5));
+        let mut octree = unit_cells(8, wall);
+        octree.fill_empty_octants();
+        let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+
+        let start = State::at(0.5, 0.5, 0.5, &octree).unwrap();
+        let goal = State::at(7.5, 0.5, 0.5, &octree).unwrap();
+        let gap = octree.locate(4.5, 7.5, 7.5).unwrap();
+
+        let path = Problem::new(&octree, Mode::Cells, start, goal)
+            .with_occupancy(&occupancy)
+            .search()
+            .unwrap();
+
+        let cells: Vec<NodeId> = path.nodes.iter().map(|node| node.state.start).collect();
+        assert!(cells.contains(&gap));
+        assert!(cells.iter().all(|cell| occupancy.is_traversable(*cell)));
+    }
+
+    #[test]
+    fn ground_paths_go_around_a_ridge() {
+        use crate::ground::{GroundConfig, GroundMap};
+        use las::point::Classification;
+
+        //flat ground at 0.5 with a ridge of height 4 across x 7..9, open for y 12..16
+        let terrain = (0..256).map(|i| {
+            let (x, y) = (i / 16, i % 16);
+            let ridge = (7..9).contains(&x) && y < 12;
+            Point {
+                classification: Classification::Ground,
+                ..point(
+                    x as f64 + 0.5,
+                    y as f64 + 0.5,
+                    if ridge { 4.5 } else { 0.5 },
+                )
+            }
+        });
+        let octree = unit_cells(16, terrain);
+        let ground = GroundMap::new(&octree, GroundConfig::default());
+
+        let start = State::at(0.5, 0.5, 0.5, &octree).unwrap();
+        let goal = State::at(15.5, 0.5, 0.5, &octree).unwrap();
+        let mut problem = Problem::new(&octree, Mode::Cells, start, goal).with_ground(&ground);
+        let path = problem.search().unwrap();
+
+        let waypoints = problem.waypoints(
+            &path,
+            point_vector(&point(0.5, 0.5, 0.5)),
+            point_vector(&point(15.5, 0.5, 0.5)),
+        );
+        assert!(waypoints.iter().all(|waypoint| waypoint.z == 0.5));
+        assert!(waypoints
+            .iter()
+            .any(|waypoint| waypoint.x == 7.5 && waypoint.y >= 12.0));
+        for node in &path.nodes {
+            if let Some(action) = &node.action {
+                assert_eq!(
+                    Some(action.cost),
+                    ground.move_cost(action.move_from, action.move_to)
+                );
+            }
+        }
+
+        let joined = problem.search_bidirectional().path.unwrap();
+        assert!((joined.total_cost - path.total_cost).abs() < 1e-9);
+        assert_chained(&joined, start, goal);
+    }
+
+    #[test]
+    fn cells_mode_finds_the_hand_computed_path() {
+        //an L of unit cells, the corner can be cut diagonally but not skipped
+        let octree = unit_cells(
+            4,
+            [(0.5, 0.5), (1.5, 0.5), (2.5, 0.5), (2.5, 1.5), (2.5, 2.5)]
+                .map(|(x, y)| point(x, y, 0.5)),
+        );
+        let start = State::at(0.5, 0.5, 0.5, &octree).unwrap();
+        let goal = State::at(2.5, 2.5, 0.5, &octree).unwrap();
+        let mut problem = Problem::new(&octree, Mode::Cells, start, goal);
+
+        let (cost, cells) = solve(&mut problem, vector(0.5, 0.5, 0.5), vector(2.5, 2.5, 0.5));
+
+        assert!((cost - (2.0 + 2f64.sqrt())).abs() < 1e-12);
+        assert_eq!(
+            cells,
+            vec![
+                vector(0.5, 0.5, 0.5),
+                vector(1.5, 0.5, 0.5),
+                vector(2.5, 1.5, 0.5),
+                vector(2.5, 2.5, 0.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn hierarchy_mode_cost_counts_every_move_once() {
+        let octree = unit_cells(
+            4,
+            [
+                point(0.5, 0.5, 0.5),
+                point(1.5, 0.5, 0.5),
+                point(3.5, 3.5, 3.5),
+            ],
+        );
+        let cell = |x: f64, y: f64, z: f64| octree.locate(x, y, z).unwrap();
+
+        //up to the shared parent and down again
+        let mut problem = Problem::new(
+            &octree,
+            Mode::Hierarchy,
+            State {
+                start: cell(0.5, 0.5, 0.5),
+            },
+            State {
+                start: cell(1.5, 0.5, 0.5),
+            },
+        );
+        let path = problem.search().unwrap();
+        assert_eq!(path.total_cost, 2.0);
+        assert_eq!(path.nodes.len(), 3);
+
+        //up to the root and down into the opposite corner
+        let mut problem = Problem::new(
+            &octree,
+            Mode::Hierarchy,
+            State {
+                start: cell(0.5, 0.5, 0.5),
+            },
+            State {
+                start: cell(3.5, 3.5, 3.5),
+            },
+        );
+        let path = problem.search().unwrap();
+        assert_eq!(path.total_cost, 4.0);
+        assert_eq!(
+            path.nodes[path.nodes.len() - 1].state.start,
+            cell(0.5, 0.5, 0.5)
+        );
+        assert_eq!(path.nodes[2].state.start, octree.root);
+        let sum: f64 = path
+            .nodes
+            .iter()
+            .filter_map(|node| node.action)
+            .map(|action| action.cost)
+            .sum();
+        assert_eq!(sum, path.total_cost);
+    }
+
+    #[test]
+    fn cells_mode_is_optimal_on_random_trees() {
+        let mut rng = Rng(0x510E_527F_ADE6_82D1);
+        for _ in 0..40 {
+            let octree = random_cells(&mut rng);
+            let leaves = leaves(&octree);
+            for _ in 0..5 {
+                let start = random_leaf(&mut rng, &leaves);
+                let goal = random_leaf(&mut rng, &leaves);
+
+                for reopen in [true, false] {
+                    let mut problem =
+                        Problem::new(&octree, Mode::Cells, start, goal).with_reopening(reopen);
+                    let expected = cheapest_cost(&problem);
+                    let found = problem.search().map(|path| path.total_cost);
+                    match (found, expected) {
+                        (Some(found), Some(expected)) => assert!((found - expected).abs() < 1e-9),
+                        (found, expected) => assert_eq!(found, expected),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn free_mode_is_optimal_on_random_trees() {
+        use crate::occupancy::{OccupancyConfig, OccupancyMap};
+
+        let mut rng = Rng(0x9B05_688C_2B3E_6C1F);
+        for _ in 0..40 {
+            let mut octree = random_cells(&mut rng);
+            octree.fill_empty_octants();
+            let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+            let free: Vec<NodeId> = leaves(&octree)
+                .into_iter()
+                .filter(|id| occupancy.is_traversable(*id))
+                .collect();
+            if free.is_empty() {
+                continue;
+            }
+            for _ in 0..5 {
+                let start = random_leaf(&mut rng, &free);
+                let goal = random_leaf(&mut rng, &free);
+
+                let mut problem =
+                    Problem::new(&octree, Mode::Cells, start, goal).with_occupancy(&occupancy);
+                let expected = cheapest_cost(&problem);
+                let path = problem.search();
+                match (path.as_ref().map(|path| path.total_cost), expected) {
+                    (Some(found), Some(expected)) => assert!((found - expected).abs() < 1e-9),
+                    (found, expected) => assert_eq!(found, expected),
+                }
+                for node in path.iter().flat_map(|path| &path.nodes) {
+                    assert!(occupancy.is_traversable(node.state.start));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn algorithms_compare_on_the_same_tree() {
+        let mut rng = Rng(0x1F83_D9AB_FB41_BD6B);
+        for _ in 0..20 {
+            let octree = random_cells(&mut rng);
+            let leaves = leaves(&octree);
+            let start = random_leaf(&mut rng, &leaves);
+            let goal = random_leaf(&mut rng, &leaves);
+            let problem = Problem::new(&octree, Mode::Cells, start, goal);
+            let cost = |algorithm: Algorithm| {
+                algorithm
+                    .run(&problem, true)
+                    .path
+                    .map(|path| path.total_cost)
+            };
+
+            let Some(cheapest) = cost(Algorithm::Dijkstra) else {
+                assert_eq!(cost(Algorithm::AStar), None);
+                continue;
+            };
+            for algorithm in [Algorithm::AStar, Algorithm::IdaStar] {
+                assert!((cost(algorithm).unwrap() - cheapest).abs() < 1e-9);
+            }
+            for epsilon in [1.5, 3.0] {
+                assert!(
+                    cost(Algorithm::WeightedAStar(epsilon)).unwrap() <= epsilon * cheapest + 1e-9
+                );
+            }
+            assert!(cost(Algorithm::GreedyBestFirst).unwrap() >= cheapest - 1e-9);
+            assert!(
+                Algorithm::AStar.run(&problem, true).expanded
+                    <= Algorithm::Dijkstra.run(&problem, true).expanded
+            );
+        }
+    }
+
+    #[test]
+    fn bidirectional_search_meets_at_the_cheapest_cost() {
+        use crate::occupancy::{OccupancyConfig, OccupancyMap};
+
+        let mut rng = Rng(0x5BE0_CD19_137E_2179);
+        for round in 0..60 {
+            let mut octree = random_cells(&mut rng);
+            if round % 2 == 1 {
+                octree.fill_empty_octants();
+            }
+            let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+            let leaves = leaves(&octree);
+            for _ in 0..5 {
+                let start = random_leaf(&mut rng, &leaves);
+                let goal = random_leaf(&mut rng, &leaves);
+
+                let mut problem = Problem::new(&octree, Mode::Cells, start, goal);
+                if round % 2 == 1 {
+                    problem = problem.with_occupancy(&occupancy);
+                }
+                let expected = cheapest_cost(&problem);
+                let search = problem.search_bidirectional();
+                match (&search.path, expected) {
+                    (Some(path), Some(expected)) => {
+                        assert!((path.total_cost - expected).abs() < 1e-9);
+                        assert_chained(path, start, goal);
+                    }
+                    (path, expected) => assert_eq!(path.is_some(), expected.is_some()),
+                }
+                if start != goal {
+                    assert!(search.forward_expanded + search.backward_expanded > 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_of_many_goals_is_the_cheapest_to_reach() {
+        let mut rng = Rng(0x6A09_E667_F3BC_C908);
+        for _ in 0..40 {
+            let octree = random_cells(&mut rng);
+            let leaves = leaves(&octree);
+            let start = random_leaf(&mut rng, &leaves);
+            let goals: Vec<State> = (0..3).map(|_| random_leaf(&mut rng, &leaves)).collect();
+
+            let nearest = goals
+                .iter()
+                .filter_map(|goal| cheapest_cost(&Problem::new(&octree, Mode::Cells, start, *goal)))
+                .min_by(f64::total_cmp);
+
+            let mut problem =
+                Problem::new(&octree, Mode::Cells, start, goals[0]).with_goals(goals.clone());
+            let path = problem.search();
+            let joined = problem.search_bidirectional().path;
+            for path in [&path, &joined] {
+                assert_eq!(path.is_some(), nearest.is_some());
+                if let (Some(path), Some(nearest)) = (path, nearest) {
+                    assert!((path.total_cost - nearest).abs() < 1e-9);
+                    assert!(goals.contains(&path.nodes[0].state));
+                    assert_chained(path, start, path.nodes[0].state);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn goal_test_stops_at_the_first_cell_that_passes() {
+        let mut rng = Rng(0xBB67_AE85_84CA_A73B);
+        for _ in 0..40 {
+            let octree = random_cells(&mut rng);
+            let leaves = leaves(&octree);
+            let start = random_leaf(&mut rng, &leaves);
+            let far_side = |state: State| octree[state.start].centre().x > 6.0;
+
+            let mut problem =
+                Problem::new(&octree, Mode::Cells, start, start).with_goals(Vec::new());
+            let costs = problem.cost_map();
+            let nearest = leaves
+                .iter()
+                .filter(|id| far_side(State { start: **id }))
+                .filter_map(|id| costs[id.0])
+                .min_by(f64::total_cmp);
+
+            problem = problem.with_goal_test(&far_side);
+            assert_eq!(problem.heuristic(start), 0.0);
+            let path = problem.search();
+            assert_eq!(path.is_some(), nearest.is_some());
+            if let (Some(path), Some(nearest)) = (path, nearest) {
+                assert!((path.total_cost - nearest).abs() < 1e-9);
+                assert!(far_side(path.nodes[0].state));
+            }
+        }
+    }
+
+    #[test]
+    fn cost_map_matches_searching_for_every_cell() {
+        use crate::occupancy::{OccupancyConfig, OccupancyMap};
+
+        let mut rng = Rng(0x3C6E_F372_FE94_F82B);
+        for round in 0..20 {
+            let mut octree = random_cells(&mut rng);
+            if round % 2 == 1 {
+                octree.fill_empty_octants();
+            }
+            let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+            let leaves = leaves(&octree);
+            let start = random_leaf(&mut rng, &leaves);
+            let problem_to = |goal: NodeId| {
+                let problem = Problem::new(&octree, Mode::Cells, start, State { start: goal });
+                if round % 2 == 1 {
+                    problem.with_occupancy(&occupancy)
+                } else {
+                    problem
+                }
+            };
+
+            let costs = problem_to(start.start).cost_map();
+            for goal in &leaves {
+                let mut problem = problem_to(*goal);
+                if !problem.is_open(*goal) && *goal != start.start {
+                    assert_eq!(costs[goal.0], None);
+                    continue;
+                }
+                match (costs[goal.0], problem.search().map(|path| path.total_cost)) {
+                    (Some(cost), Some(expected)) => assert!((cost - expected).abs() < 1e-9),
+                    (cost, expected) => assert_eq!(cost, expected),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn multi_goal_heuristic_never_overestimates() {
+        let mut rng = Rng(0xA54F_F53A_5F1D_36F1);
+        for _ in 0..20 {
+            let octree = random_cells(&mut rng);
+            let leaves = leaves(&octree);
+            let goals: Vec<State> = (0..4).map(|_| random_leaf(&mut rng, &leaves)).collect();
+            let problem =
+                Problem::new(&octree, Mode::Cells, goals[0], goals[0]).with_goals(goals.clone());
+
+            //moves cost the same both ways, so a flood from each goal gives the cost to it
+            let to_goals: Vec<Vec<Option<f64>>> = goals
+                .iter()
+                .map(|goal| Problem::new(&octree, Mode::Cells, *goal, *goal).cost_map())
+                .collect();
+            for id in &leaves {
+                let nearest = to_goals
+                    .iter()
+                    .filter_map(|costs| costs[id.0])
+                    .fold(f64::INFINITY, f64::min);
+                assert!(problem.heuristic(State { start: *id }) <= nearest + 1e-9);
+            }
+        }
     }
 }
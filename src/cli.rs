@@ -0,0 +1,555 @@
+use std::fmt;
+
+use las::{Bounds, Vector};
+
+use crate::{
+    a_star::Mode, ground::GroundConfig, model::OctreeConfig, occupancy::OccupancyConfig,
+    region::PolygonPrism, search::Algorithm,
+};
+
+pub const USAGE: &str = "usage:
+    lsa_octree_challenge build <file.las> [OPTIONS]
+    lsa_octree_challenge stats <file.las> [OPTIONS]
+    lsa_octree_challenge query <file.las> --min X,Y,Z --max X,Y,Z [OPTIONS]
+    lsa_octree_challenge query <file.las> --polygon X,Y;X,Y;X,Y... [--min-z Z] [--max-z Z] [OPTIONS]
+    lsa_octree_challenge nearest <file.las> --at X,Y,Z [--k N] [OPTIONS]
+    lsa_octree_challenge radius <file.las> --at X,Y,Z --radius R [OPTIONS]
+    lsa_octree_challenge path  <file.las> --start <INDEX|X,Y,Z> --goal <INDEX|X,Y,Z> [--mode tree|cells|free|ground] [OPTIONS]
+
+options:
+    --max-depth N    deepest level of the octree (default 16)
+    --leaf-size N    points a leaf holds before it is split (default 32)
+    --min-edge M     smallest edge length a node is split down to (default 0.1)
+    --stream         insert points one by one while reading instead of bulk loading
+
+path options:
+    --goal P         can be given more than once, the path goes to the nearest goal
+    --algorithm A    astar, dijkstra, greedy, weighted or ida (default astar)
+    --epsilon E      heuristic weight of the weighted algorithm, at least 1 (default 1.5)
+    --bidirectional  A* from both ends at once, meeting in the middle
+    --coarse-depth N plan over the nodes at depth N first, then refine at the leaves,
+                     neither this nor --bidirectional takes --algorithm or --epsilon
+    --smooth         cut corners where the straight line stays clear of closed cells,
+                     only in free and ground mode
+
+free mode options:
+    --min-points N     points that make a cell occupied (default 1)
+    --agent-radius R   keep at least this far from occupied cells (default 0)
+    --scanner X,Y,Z    scanner position, cells are only free when a ray from a scanner
+                       to a point crosses them, can be given more than once
+
+ground mode options:
+    --max-slope G      steepest rise over run walked between cells (default 0.5)
+    --max-step H       highest step taken whatever the slope (default 0.3)
+    --slope-weight W   cost of a move is its length times 1 + W * slope (default 1)";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PointRef {
+    Index(usize),
+    Coordinates(Vector<f64>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryRegion {
+    Box(Bounds),
+    Prism(PolygonPrism),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Build,
+    Stats,
+    Query {
+        region: QueryRegion,
+    },
+    Nearest {
+        at: Vector<f64>,
+        k: usize,
+    },
+    Radius {
+        at: Vector<f64>,
+        radius: f64,
+    },
+    Path {
+        start: PointRef,
+        goals: Vec<PointRef>,
+        mode: Mode,
+        algorithm: Algorithm,
+        bidirectional: bool,
+        coarse_depth: Option<i32>,
+        smooth: bool,
+        occupancy: Option<OccupancyConfig>,
+        ground: Option<GroundConfig>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub input: String,
+    pub config: OctreeConfig,
+    pub stream: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ArgsError {
+    MissingCommand,
+    UnknownCommand(String),
+    MissingInput,
+    MissingOption(&'static str),
+    MissingValue(String),
+    InvalidValue(String, String),
+    UnexpectedArgument(String),
+    ConflictingOptions(String, String),
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgsError::MissingCommand => write!(f, "no command given"),
+            ArgsError::UnknownCommand(command) => write!(f, "unknown command `{}`", command),
+            ArgsError::MissingInput => write!(f, "no input LAS file given"),
+            ArgsError::MissingOption(option) => write!(f, "missing required option `{}`", option),
+            ArgsError::MissingValue(option) => write!(f, "option `{}` needs a value", option),
+            ArgsError::InvalidValue(option, value) => {
+                write!(f, "invalid value `{}` for option `{}`", value, option)
+            }
+            ArgsError::UnexpectedArgument(arg) => write!(f, "unexpected argument `{}`", arg),
+            ArgsError::ConflictingOptions(first, second) => {
+                write!(f, "option `{}` conflicts with `{}`", second, first)
+            }
+        }
+    }
+}
+
+impl Args {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, ArgsError> {
+        let mut args = args.into_iter();
+
+        let command = args.next().ok_or(ArgsError::MissingCommand)?;
+
+        let mut input = None;
+        let mut config = OctreeConfig::default();
+        let mut min = None;
+        let mut max = None;
+        let mut start = None;
+        let mut goals = Vec::new();
+        let mut mode = Mode::Hierarchy;
+        let mut mode_given: Option<String> = None;
+        let mut algorithm = Algorithm::AStar;
+        let mut epsilon = 1.5;
+        let mut search_given: Option<String> = None;
+        let mut free = false;
+        let mut occupancy = OccupancyConfig::default();
+        let mut walk = false;
+        let mut ground = GroundConfig::default();
+        let mut stream = false;
+        let mut bidirectional = false;
+        let mut coarse_depth = None;
+        let mut smooth = false;
+        let mut at = None;
+        let mut k = 10;
+        let mut radius = None;
+        let mut polygon = None;
+        let mut min_z = f64::NEG_INFINITY;
+        let mut max_z = f64::INFINITY;
+
+        while let Some(arg) = args.next() {
+            if arg == "--stream" {
+                stream = true;
+            } else if arg == "--bidirectional" {
+                bidirectional = true;
+            } else if arg == "--smooth" {
+                smooth = true;
+            } else if arg.starts_with("--") {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ArgsError::MissingValue(arg.clone()))?;
+                match arg.as_str() {
+                    "--max-depth" => {
+                        config.max_depth = match value.parse() {
+                            Ok(depth) if depth > 0 => depth,
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--leaf-size" => {
+                        config.max_points_per_leaf = match value.parse() {
+                            Ok(size) => size,
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--min-edge" => {
+                        config.min_edge_length = match value.parse() {
+                            Ok(length) if length >= 0.0 => length,
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--min" => min = Some(parse_vector(&arg, &value)?),
+                    "--max" => max = Some(parse_vector(&arg, &value)?),
+                    "--polygon" => polygon = Some(parse_polygon(&arg, &value)?),
+                    "--min-z" => {
+                        min_z = value
+                            .parse()
+                            .map_err(|_| ArgsError::InvalidValue(arg.clone(), value.clone()))?
+                    }
+                    "--max-z" => {
+                        max_z = value
+                            .parse()
+                            .map_err(|_| ArgsError::InvalidValue(arg.clone(), value.clone()))?
+                    }
+                    "--at" => at = Some(parse_vector(&arg, &value)?),
+                    "--k" => {
+                        k = match value.parse() {
+                            Ok(k) if k > 0 => k,
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--radius" => {
+                        radius = match value.parse() {
+                            Ok(radius) if radius >= 0.0 => Some(radius),
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--start" => start = Some(parse_point_ref(&arg, &value)?),
+                    "--goal" => goals.push(parse_point_ref(&arg, &value)?),
+                    "--mode" => {
+                        if let Some(given) = mode_given.filter(|given| *given != value) {
+                            return Err(ArgsError::ConflictingOptions(
+                                format!("--mode {}", given),
+                                format!("--mode {}", value),
+                            ));
+                        }
+                        mode_given = Some(value.clone());
+                        mode = match value.as_str() {
+                            "tree" => Mode::Hierarchy,
+                            "cells" => Mode::Cells,
+                            "free" => {
+                                free = true;
+                                Mode::Cells
+                            }
+                            "ground" => {
+                                walk = true;
+                                Mode::Cells
+                            }
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--algorithm" => {
+                        search_given = Some(arg.clone());
+                        algorithm = match value.as_str() {
+                            "astar" => Algorithm::AStar,
+                            "dijkstra" => Algorithm::Dijkstra,
+                            "greedy" => Algorithm::GreedyBestFirst,
+                            "weighted" => Algorithm::WeightedAStar(epsilon),
+                            "ida" => Algorithm::IdaStar,
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--epsilon" => {
+                        search_given = Some(arg.clone());
+                        epsilon = match value.parse() {
+                            Ok(epsilon) if epsilon >= 1.0 => epsilon,
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--coarse-depth" => {
+                        coarse_depth = match value.parse() {
+                            Ok(depth) if depth >= 0 => Some(depth),
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--min-points" => {
+                        occupancy.min_points = match value.parse() {
+                            Ok(count) if count > 0 => count,
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--agent-radius" => {
+                        occupancy.agent_radius = match value.parse() {
+                            Ok(radius) if radius >= 0.0 => radius,
+                            _ => return Err(ArgsError::InvalidValue(arg, value)),
+                        }
+                    }
+                    "--scanner" => occupancy.scanners.push(parse_vector(&arg, &value)?),
+                    "--max-slope" => ground.max_slope = parse_non_negative(&arg, &value)?,
+                    "--max-step" => ground.max_step = parse_non_negative(&arg, &value)?,
+                    "--slope-weight" => ground.slope_weight = parse_non_negative(&arg, &value)?,
+                    _ => return Err(ArgsError::UnexpectedArgument(arg)),
+                }
+            } else if input.is_none() {
+                input = Some(arg);
+            } else {
+                return Err(ArgsError::UnexpectedArgument(arg));
+            }
+        }
+
+        let command = match command.as_str() {
+            "build" => Command::Build,
+            "stats" => Command::Stats,
+            "query" => Command::Query {
+                region: match polygon {
+                    Some(polygon) => QueryRegion::Prism(PolygonPrism {
+                        polygon,
+                        min_z,
+                        max_z,
+                    }),
+                    None => QueryRegion::Box(Bounds {
+                        min: min.ok_or(ArgsError::MissingOption("--min"))?,
+                        max: max.ok_or(ArgsError::MissingOption("--max"))?,
+                    }),
+                },
+            },
+            "nearest" => Command::Nearest {
+                at: at.ok_or(ArgsError::MissingOption("--at"))?,
+                k,
+            },
+            "radius" => Command::Radius {
+                at: at.ok_or(ArgsError::MissingOption("--at"))?,
+                radius: radius.ok_or(ArgsError::MissingOption("--radius"))?,
+            },
+            "path" if bidirectional && coarse_depth.is_some() => {
+                return Err(ArgsError::ConflictingOptions(
+                    "--coarse-depth".to_string(),
+                    "--bidirectional".to_string(),
+                ))
+            }
+            //the bidirectional and the cluster search are A* of their own
+            "path" if search_given.is_some() && (bidirectional || coarse_depth.is_some()) => {
+                return Err(ArgsError::ConflictingOptions(
+                    if bidirectional {
+                        "--bidirectional"
+                    } else {
+                        "--coarse-depth"
+                    }
+                    .to_string(),
+                    search_given.unwrap_or_default(),
+                ))
+            }
+            "path" if smooth && !free && !walk => {
+                return Err(ArgsError::ConflictingOptions(
+                    format!("--mode {}", mode_given.as_deref().unwrap_or("tree")),
+                    "--smooth".to_string(),
+                ))
+            }
+            "path" => Command::Path {
+                start: start.ok_or(ArgsError::MissingOption("--start"))?,
+                goals: if goals.is_empty() {
+                    return Err(ArgsError::MissingOption("--goal"));
+                } else {
+                    goals
+                },
+                mode,
+                algorithm: match algorithm {
+                    Algorithm::WeightedAStar(_) => Algorithm::WeightedAStar(epsilon),
+                    algorithm => algorithm,
+                },
+                bidirectional,
+                coarse_depth,
+                smooth,
+                occupancy: if free { Some(occupancy) } else { None },
+                ground: if walk { Some(ground) } else { None },
+            },
+            _ => return Err(ArgsError::UnknownCommand(command)),
+        };
+
+        Ok(Args {
+            command,
+            input: input.ok_or(ArgsError::MissingInput)?,
+            config,
+            stream,
+        })
+    }
+}
+
+fn parse_non_negative(option: &str, value: &str) -> Result<f64, ArgsError> {
+    match value.parse() {
+        Ok(number) if number >= 0.0 => Ok(number),
+        _ => Err(ArgsError::InvalidValue(
+            option.to_string(),
+            value.to_string(),
+        )),
+    }
+}
+
+fn parse_vector(option: &str, value: &str) -> Result<Vector<f64>, ArgsError> {
+    let invalid = || ArgsError::InvalidValue(option.to_string(), value.to_string());
+
+    let coordinates = value
+        .split(',')
+        .map(|part| part.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+
+    match coordinates[..] {
+        [x, y, z] => Ok(Vector { x, y, z }),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_polygon(option: &str, value: &str) -> Result<Vec<(f64, f64)>, ArgsError> {
+    let invalid = || ArgsError::InvalidValue(option.to_string(), value.to_string());
+
+    let polygon = value
+        .split(';')
+        .map(|vertex| {
+            let mut coordinates = vertex.split(',').map(|part| part.trim().parse::<f64>());
+            match (coordinates.next(), coordinates.next(), coordinates.next()) {
+                (Some(Ok(x)), Some(Ok(y)), None) => Ok((x, y)),
+                _ => Err(invalid()),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if polygon.len() < 3 {
+        return Err(invalid());
+    }
+    Ok(polygon)
+}
+
+fn parse_point_ref(option: &str, value: &str) -> Result<PointRef, ArgsError> {
+    if value.contains(',') {
+        parse_vector(option, value).map(PointRef::Coordinates)
+    } else {
+        value
+            .parse()
+            .map(PointRef::Index)
+            .map_err(|_| ArgsError::InvalidValue(option.to_string(), value.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, ArgsError> {
+        Args::parse(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn path_options_are_collected() {
+        let args =
+            parse("path in.las --start 1 --goal 2,3,4 --goal 5 --mode free --smooth").unwrap();
+        assert_eq!(args.input, "in.las");
+        match args.command {
+            Command::Path {
+                start,
+                goals,
+                mode,
+                smooth,
+                occupancy,
+                ground,
+                ..
+            } => {
+                assert_eq!(start, PointRef::Index(1));
+                assert_eq!(
+                    goals,
+                    vec![
+                        PointRef::Coordinates(Vector {
+                            x: 2.0,
+                            y: 3.0,
+                            z: 4.0
+                        }),
+                        PointRef::Index(5)
+                    ]
+                );
+                assert_eq!(mode, Mode::Cells);
+                assert!(smooth);
+                assert_eq!(occupancy, Some(OccupancyConfig::default()));
+                assert_eq!(ground, None);
+            }
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    #[test]
+    fn missing_command_input_and_values_are_reported() {
+        assert_eq!(parse(""), Err(ArgsError::MissingCommand));
+        assert_eq!(parse("build"), Err(ArgsError::MissingInput));
+        assert_eq!(
+            parse("build in.las --max-depth"),
+            Err(ArgsError::MissingValue("--max-depth".to_string()))
+        );
+        assert_eq!(
+            parse("path in.las --goal 2"),
+            Err(ArgsError::MissingOption("--start"))
+        );
+        assert_eq!(
+            parse("path in.las --start 1"),
+            Err(ArgsError::MissingOption("--goal"))
+        );
+        assert_eq!(
+            parse("radius in.las --at 1,2,3"),
+            Err(ArgsError::MissingOption("--radius"))
+        );
+    }
+
+    #[test]
+    fn unknown_commands_flags_and_values_are_rejected() {
+        assert_eq!(
+            parse("walk in.las"),
+            Err(ArgsError::UnknownCommand("walk".to_string()))
+        );
+        assert_eq!(
+            parse("build in.las --depth 3"),
+            Err(ArgsError::UnexpectedArgument("--depth".to_string()))
+        );
+        assert_eq!(
+            parse("build in.las other.las"),
+            Err(ArgsError::UnexpectedArgument("other.las".to_string()))
+        );
+        assert_eq!(
+            parse("path in.las --start 1 --goal 2 --mode fly"),
+            Err(ArgsError::InvalidValue(
+                "--mode".to_string(),
+                "fly".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("query in.las --min 1,2 --max 3,4,5"),
+            Err(ArgsError::InvalidValue(
+                "--min".to_string(),
+                "1,2".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn conflicting_modes_are_rejected() {
+        assert_eq!(
+            parse("path in.las --start 1 --goal 2 --mode free --mode ground"),
+            Err(ArgsError::ConflictingOptions(
+                "--mode free".to_string(),
+                "--mode ground".to_string()
+            ))
+        );
+        assert!(parse("path in.las --start 1 --goal 2 --mode cells --mode cells").is_ok());
+        assert_eq!(
+            parse("path in.las --start 1 --goal 2 --mode cells --smooth"),
+            Err(ArgsError::ConflictingOptions(
+                "--mode cells".to_string(),
+                "--smooth".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("path in.las --start 1 --goal 2 --coarse-depth 3 --bidirectional"),
+            Err(ArgsError::ConflictingOptions(
+                "--coarse-depth".to_string(),
+                "--bidirectional".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("path in.las --start 1 --goal 2 --algorithm dijkstra --bidirectional"),
+            Err(ArgsError::ConflictingOptions(
+                "--bidirectional".to_string(),
+                "--algorithm".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("path in.las --start 1 --goal 2 --coarse-depth 3 --epsilon 2"),
+            Err(ArgsError::ConflictingOptions(
+                "--coarse-depth".to_string(),
+                "--epsilon".to_string()
+            ))
+        );
+    }
+}
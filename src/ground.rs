@@ -0,0 +1,164 @@
+use las::{point::Classification, Vector};
+
+use crate::model::{Adjacency, NodeId, Octree};
+
+//A move between two ground cells is allowed when the height change is a step the walker can
+//take, or when the slope (rise over run) is gentle enough. Costs are the distance
+//multiplied by 1 + slope_weight * slope.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GroundConfig {
+    pub max_slope: f64,
+    pub max_step: f64,
+    pub slope_weight: f64,
+}
+
+impl Default for GroundConfig {
+    fn default() -> Self {
+        GroundConfig {
+            max_slope: 0.5,
+            max_step: 0.3,
+            slope_weight: 1.0,
+        }
+    }
+}
+
+//Ground surface by NodeId, the mean of the ground-classified points of every leaf that has
+//any. Walking happens on these points, so the planning is 2.5D even though the cells are 3D.
+#[derive(Clone, Debug)]
+pub struct GroundMap {
+    pub surface: Vec<Option<Vector<f64>>>,
+    pub config: GroundConfig,
+}
+
+impl GroundMap {
+    pub fn new(tree: &Octree, config: GroundConfig) -> Self {
+        let mut surface = vec![None; tree.nodes.len()];
+
+        for (id, node) in tree.iter_nodes().filter(|(_, node)| node.is_leaf()) {
+            let ground: Vec<_> = node
+                .points
+                .iter()
+                .filter(|point| point.classification == Classification::Ground)
+                .collect();
+
+            if !ground.is_empty() {
+                let count = ground.len() as f64;
+                surface[id.0] = Some(Vector {
+                    x: ground.iter().map(|point| point.x).sum::<f64>() / count,
+                    y: ground.iter().map(|point| point.y).sum::<f64>() / count,
+                    z: ground.iter().map(|point| point.z).sum::<f64>() / count,
+                });
+            }
+        }
+
+        GroundMap { surface, config }
+    }
+
+    pub fn surface(&self, id: NodeId) -> Option<Vector<f64>> {
+        self.surface[id.0]
+    }
+
+    //Cost of walking between two ground cells, None when either is not ground or the move
+    //is neither a small enough step nor a gentle enough slope. A step is weighted like the
+    //steepest slope allowed.
+    pub fn move_cost(&self, from: NodeId, to: NodeId) -> Option<f64> {
+        let (a, b) = (self.surface(from)?, self.surface(to)?);
+        let run = ((b.x - a.x).powi(2) + (b.y - a.y).powi(2)).sqrt();
+        let rise = (b.z - a.z).abs();
+        let slope = if run > 0.0 { rise / run } else { f64::INFINITY };
+
+        if rise > self.config.max_step && slope > self.config.max_slope {
+            return None;
+        }
+
+        let distance = (run * run + rise * rise).sqrt();
+        Some(distance * (1.0 + self.config.slope_weight * slope.min(self.config.max_slope)))
+    }
+
+    //Ground cells touching the cell that can be walked to, with the cost of the move.
+    pub fn successors(&self, tree: &Octree, id: NodeId) -> Vec<(NodeId, f64)> {
+        tree.neighbours(id, Adjacency::Vertex)
+            .into_iter()
+            .filter_map(|neighbour| Some((neighbour, self.move_cost(id, neighbour)?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{point, unit_cells};
+    use las::Point;
+
+    fn ground(x: f64, y: f64, z: f64) -> Point {
+        Point {
+            classification: Classification::Ground,
+            ..point(x, y, z)
+        }
+    }
+
+    #[test]
+    fn only_ground_points_make_a_surface() {
+        let vegetation = Point {
+            classification: Classification::HighVegetation,
+            ..ground(2.5, 2.5, 2.5)
+        };
+        let octree = unit_cells(
+            16,
+            [ground(0.2, 0.4, 0.5), ground(0.4, 0.6, 0.7), vegetation],
+        );
+        let map = GroundMap::new(&octree, GroundConfig::default());
+
+        let surface = map.surface(octree.locate(0.5, 0.5, 0.5).unwrap()).unwrap();
+        assert!((surface.x - 0.3).abs() < 1e-9);
+        assert!((surface.y - 0.5).abs() < 1e-9);
+        assert!((surface.z - 0.6).abs() < 1e-9);
+        assert_eq!(map.surface(octree.locate(2.5, 2.5, 2.5).unwrap()), None);
+    }
+
+    #[test]
+    fn moves_are_limited_by_step_and_slope() {
+        let octree = unit_cells(
+            16,
+            [
+                ground(0.5, 0.5, 0.5),
+                ground(1.5, 0.5, 0.7),
+                ground(2.5, 0.5, 1.5),
+                ground(0.5, 1.5, 0.5),
+            ],
+        );
+        let cell = |x: f64, y: f64, z: f64| octree.locate(x, y, z).unwrap();
+        let (flat, gentle, steep, level) = (
+            cell(0.5, 0.5, 0.5),
+            cell(1.5, 0.5, 0.7),
+            cell(2.5, 0.5, 1.5),
+            cell(0.5, 1.5, 0.5),
+        );
+        let map = GroundMap::new(&octree, GroundConfig::default());
+
+        //a rise of 0.2 is a step, 0.8 over a run of 1 is too steep for both limits
+        assert!(map.move_cost(flat, gentle).is_some());
+        assert_eq!(map.move_cost(gentle, steep), None);
+        assert_eq!(map.move_cost(flat, level), Some(1.0));
+        assert!(map.move_cost(flat, gentle).unwrap() > map.move_cost(flat, level).unwrap());
+
+        let relaxed = GroundMap::new(
+            &octree,
+            GroundConfig {
+                max_slope: 1.0,
+                ..Default::default()
+            },
+        );
+        assert!(relaxed.move_cost(gentle, steep).is_some());
+
+        let mut successors: Vec<NodeId> = map
+            .successors(&octree, flat)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        successors.sort();
+        let mut expected = vec![gentle, level];
+        expected.sort();
+        assert_eq!(successors, expected);
+    }
+}
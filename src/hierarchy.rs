@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::a_star::{distance, Action, ActionStatePair, Mode, Node, Path, Problem, State};
+use crate::model::{Comparison, NodeId, Octree};
+use crate::search::{construct_path, Algorithm, SearchProblem};
+
+//Coarse graph over the nodes at one level of the tree, each a cluster of the leaves below
+//it. Two clusters are linked when a move leads from an open leaf of one to an open leaf of
+//the other. Start and goal play no part, so one graph serves every query on a tree with the
+//same occupancy and ground.
+#[derive(Clone, Debug)]
+pub struct AbstractGraph {
+    pub level: i32,
+    //nodes of the tree the graph was built for, a tree that grew since is not searched
+    pub node_count: usize,
+    //cluster of every open leaf by NodeId, the node above it at the level or the leaf itself
+    //when it is not as deep
+    pub clusters: Vec<Option<NodeId>>,
+    //linked clusters with the distance between their centres
+    pub edges: HashMap<NodeId, Vec<(NodeId, f64)>>,
+}
+
+impl AbstractGraph {
+    //Only the tree, mode, occupancy and ground of the problem are used.
+    pub fn new(problem: &Problem, level: i32) -> Self {
+        let tree = problem.tree;
+        let mut clusters = vec![None; tree.nodes.len()];
+
+        for (id, _) in tree
+            .iter_nodes()
+            .filter(|(id, node)| node.is_leaf() && problem.is_open(*id))
+        {
+            let mut cluster = id;
+            while tree[cluster].depth > level {
+                match tree[cluster].parent {
+                    Some(parent) => cluster = parent,
+                    None => break,
+                }
+            }
+            clusters[id.0] = Some(cluster);
+        }
+
+        let mut edges: HashMap<NodeId, Vec<(NodeId, f64)>> = HashMap::new();
+        for (leaf, from) in clusters.iter().enumerate() {
+            let Some(from) = *from else {
+                continue;
+            };
+            for next in problem.moves(State {
+                start: NodeId(leaf),
+            }) {
+                let Some(to) = clusters[next.state.start.0] else {
+                    continue;
+                };
+                let linked = edges.entry(from).or_default();
+                if to != from && linked.iter().all(|(other, _)| *other != to) {
+                    linked.push((to, distance(&tree[from].centre(), &tree[to].centre())));
+                }
+            }
+        }
+
+        AbstractGraph {
+            level,
+            node_count: tree.nodes.len(),
+            clusters,
+            edges,
+        }
+    }
+
+    pub fn cluster(&self, id: NodeId) -> Option<NodeId> {
+        self.clusters.get(id.0).copied().flatten()
+    }
+
+    //Clusters a path may leave the start or enter a goal from: the cluster of the cell, or
+    //when the cell is not open, the clusters of the open cells a move away, the same way a
+    //search steps out of a start or into a goal that is not open.
+    fn end_clusters(&self, problem: &Problem, id: NodeId) -> Vec<NodeId> {
+        if let Some(cluster) = self.cluster(id) {
+            return vec![cluster];
+        }
+        let mut clusters = Vec::new();
+        for next in problem.moves(State { start: id }) {
+            if let Some(cluster) = self.cluster(next.state.start) {
+                if !clusters.contains(&cluster) {
+                    clusters.push(cluster);
+                }
+            }
+        }
+        clusters
+    }
+
+    //Plans a path between the clusters of the start and the goal first, then refines it at
+    //leaf resolution one pair of clusters at a time, each step searching only the two. The
+    //path found need not be the cheapest. When the clusters can not be used the search goes
+    //over all leaves instead, and the result tells why.
+    pub fn search(&self, problem: &mut Problem) -> Refined {
+        let fallback = |problem: &mut Problem, reason| Refined {
+            path: problem.search(),
+            fallback: Some(reason),
+        };
+        if problem.tree.nodes.len() != self.node_count {
+            return fallback(problem, Fallback::Stale);
+        }
+        if problem.mode != Mode::Cells || problem.goal_test.is_some() {
+            return fallback(problem, Fallback::Unsupported);
+        }
+
+        let start = problem.start_state;
+        let exits = self.end_clusters(problem, start.start);
+        let goals: Vec<NodeId> = problem
+            .goals
+            .iter()
+            .flat_map(|goal| self.end_clusters(problem, goal.start))
+            .collect();
+        if exits.is_empty() || goals.is_empty() {
+            return fallback(problem, Fallback::Unattached);
+        }
+
+        //a start that is not open is a node of its own, linked to the clusters around it
+        let from = self.cluster(start.start).unwrap_or(start.start);
+        let tree = problem.tree;
+        let coarse = Coarse {
+            graph: self,
+            tree,
+            start: from,
+            exits: if self.cluster(start.start).is_none() {
+                exits
+                    .iter()
+                    .map(|exit| (*exit, distance(&tree[from].centre(), &tree[*exit].centre())))
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            goals,
+        };
+        let search = Algorithm::AStar.run(&coarse, false);
+        problem.nodes_visited += search.generated as i32;
+        //every move between leaves is an edge here, so no coarse path means no path at all
+        let Some(route) = search.path else {
+            return Refined {
+                path: None,
+                fallback: None,
+            };
+        };
+        let route: Vec<NodeId> = route
+            .nodes
+            .iter()
+            .rev()
+            .map(|node| node.state.start)
+            .collect();
+
+        let steps: Vec<(NodeId, NodeId)> = if route.len() == 1 {
+            vec![(from, from)]
+        } else {
+            route.windows(2).map(|pair| (pair[0], pair[1])).collect()
+        };
+
+        let mut nodes = vec![Node {
+            state: start,
+            parent: None,
+            action: None,
+            g: 0.0,
+        }];
+        for (step, (from, to)) in steps.iter().enumerate() {
+            let segment = Segment {
+                problem,
+                graph: self,
+                start: nodes[nodes.len() - 1].state,
+                from: *from,
+                to: *to,
+                last: step == steps.len() - 1,
+            };
+            let search = Algorithm::AStar.run(&segment, problem.reopen);
+            problem.nodes_visited += search.generated as i32;
+            let Some(path) = search.path else {
+                return fallback(problem, Fallback::Stuck);
+            };
+
+            for node in path.nodes.iter().rev().skip(1) {
+                let cost = node.action.map_or(0.0, |action| action.cost);
+                nodes.push(Node {
+                    parent: Some(nodes.len() - 1),
+                    g: nodes[nodes.len() - 1].g + cost,
+                    ..node.clone()
+                });
+            }
+        }
+
+        Refined {
+            path: Some(construct_path(&nodes, nodes.len() - 1)),
+            fallback: None,
+        }
+    }
+}
+
+//Path found over the graph, or by the search over all leaves it fell back to and why.
+#[derive(Debug)]
+pub struct Refined {
+    pub path: Option<Path>,
+    pub fallback: Option<Fallback>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fallback {
+    //the mode is not cells or the goals are given by a test
+    Unsupported,
+    //the start or every goal is a cell that is not open and has no open cell next to it
+    Unattached,
+    //a step between two clusters found no way through them
+    Stuck,
+    //the tree has other nodes than the one the graph was built for
+    Stale,
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fallback::Unsupported => write!(f, "clusters are only used for cells and goal cells"),
+            Fallback::Unattached => {
+                write!(f, "the start or every goal has no open cell next to it")
+            }
+            Fallback::Stuck => write!(f, "no way was found between two linked clusters"),
+            Fallback::Stale => write!(f, "the graph was built for another tree"),
+        }
+    }
+}
+
+//Search over the clusters, moves follow the edges of the graph.
+struct Coarse<'a> {
+    graph: &'a AbstractGraph,
+    tree: &'a Octree,
+    start: NodeId,
+    //moves out of a start that is not a cluster
+    exits: Vec<(NodeId, f64)>,
+    goals: Vec<NodeId>,
+}
+
+impl SearchProblem for Coarse<'_> {
+    fn start(&self) -> State {
+        State { start: self.start }
+    }
+
+    fn successors(&self, state: State) -> Vec<ActionStatePair> {
+        let linked = match self.graph.edges.get(&state.start) {
+            Some(edges) => edges,
+            None if state.start == self.start => &self.exits,
+            None => return Vec::new(),
+        };
+        linked
+            .iter()
+            .map(|(next, cost)| ActionStatePair {
+                action: Action {
+                    move_to: *next,
+                    move_from: state.start,
+                    cost: *cost,
+                },
+                state: State { start: *next },
+            })
+            .collect()
+    }
+
+    fn is_goal(&self, state: State) -> bool {
+        self.goals.contains(&state.start)
+    }
+
+    fn heuristic(&self, state: State) -> f64 {
+        let centre = self.tree[state.start].centre();
+        self.goals
+            .iter()
+            .map(|goal| distance(&centre, &self.tree[*goal].centre()))
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+//Leaf search from a cell in one cluster into the next cluster, or to the goal on the last
+//step, through the leaves of the two clusters only. The start and goals may lie outside
+//them when they are not open.
+struct Segment<'a, 'b> {
+    problem: &'a Problem<'b>,
+    graph: &'a AbstractGraph,
+    start: State,
+    from: NodeId,
+    to: NodeId,
+    last: bool,
+}
+
+impl SearchProblem for Segment<'_, '_> {
+    fn start(&self) -> State {
+        self.start
+    }
+
+    fn successors(&self, state: State) -> Vec<ActionStatePair> {
+        self.problem
+            .successors(state)
+            .into_iter()
+            .filter(|child| {
+                (self.last && self.problem.is_goal(child.state))
+                    || self
+                        .graph
+                        .cluster(child.state.start)
+                        .is_some_and(|cluster| cluster == self.from || cluster == self.to)
+            })
+            .collect()
+    }
+
+    fn is_goal(&self, state: State) -> bool {
+        if self.last {
+            self.problem.is_goal(state)
+        } else {
+            self.graph.cluster(state.start) == Some(self.to)
+        }
+    }
+
+    fn heuristic(&self, state: State) -> f64 {
+        if self.last {
+            self.problem.heuristic(state)
+        } else {
+            //no cell of the next cluster is nearer than its box
+            let bounds = self.problem.tree[self.to].bounds;
+            bounds
+                .distance_squared_to(&self.problem.position(state.start))
+                .sqrt()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::occupancy::{OccupancyConfig, OccupancyMap};
+    use crate::test_util::{grid, point, unit_cells, wall};
+
+    fn cell(octree: &Octree, x: f64, y: f64, z: f64) -> State {
+        State::at(x, y, z, octree).unwrap()
+    }
+
+    //Nodes from the goal back to the start, each reached by a move from the one after it.
+    fn assert_walkable(path: &Path, problem: &Problem) {
+        assert!(problem.goals.contains(&path.nodes.first().unwrap().state));
+        assert_eq!(path.nodes.last().unwrap().state, problem.start_state);
+        for pair in path.nodes.windows(2) {
+            assert!(problem
+                .successors(pair[1].state)
+                .iter()
+                .any(|child| child.state == pair[0].state));
+        }
+        let cost: f64 = path
+            .nodes
+            .iter()
+            .flat_map(|node| node.action)
+            .map(|action| action.cost)
+            .sum();
+        assert!((cost - path.total_cost).abs() < 1e-9);
+    }
+
+    #[test]
+    fn open_leaves_belong_to_their_cluster_at_the_level() {
+        let octree = wall();
+        let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+        let start = cell(&octree, 0.5, 0.5, 0.5);
+        let problem = Problem::new(&octree, Mode::Cells, start, start).with_occupancy(&occupancy);
+        let graph = AbstractGraph::new(&problem, 1);
+
+        for (id, node) in octree.iter_nodes().filter(|(_, node)| node.is_leaf()) {
+            match graph.cluster(id) {
+                Some(cluster) => {
+                    assert!(occupancy.is_traversable(id));
+                    assert!(octree[cluster].depth <= 1);
+                    assert!(cluster == id || octree.is_ancestor(cluster, id));
+                    assert!(node.depth <= 1 || octree[cluster].depth == 1);
+                }
+                None => assert!(!occupancy.is_traversable(id)),
+            }
+        }
+
+        //the wall splits the cube, only the clusters beside the gap link across it
+        for (from, linked) in &graph.edges {
+            for (to, _) in linked {
+                let (a, b) = (octree[*from].centre(), octree[*to].centre());
+                if (a.x < 4.0) != (b.x < 4.0) {
+                    assert!(a.y >= 4.0 && a.z >= 4.0 && b.y >= 4.0 && b.z >= 4.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn coarse_route_is_refined_through_the_gap() {
+        let octree = wall();
+        let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+        let start = cell(&octree, 0.5, 0.5, 0.5);
+        let goal = cell(&octree, 7.5, 0.5, 0.5);
+        let gap = cell(&octree, 4.5, 7.5, 7.5);
+
+        let mut problem =
+            Problem::new(&octree, Mode::Cells, start, goal).with_occupancy(&occupancy);
+        let graph = AbstractGraph::new(&problem, 1);
+        let refined = graph.search(&mut problem);
+        assert_eq!(refined.fallback, None);
+        let path = refined.path.unwrap();
+        let cheapest = problem.search().unwrap();
+
+        assert_walkable(&path, &problem);
+        assert!(path.nodes.iter().any(|node| node.state == gap));
+        assert!(path.total_cost >= cheapest.total_cost - 1e-9);
+    }
+
+    #[test]
+    fn one_graph_answers_every_query() {
+        let octree = wall();
+        let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+        let open: Vec<State> = octree
+            .iter_nodes()
+            .filter(|(id, node)| node.is_leaf() && occupancy.is_traversable(*id))
+            .map(|(id, _)| State { start: id })
+            .collect();
+        let template =
+            Problem::new(&octree, Mode::Cells, open[0], open[0]).with_occupancy(&occupancy);
+
+        for level in 0..4 {
+            let graph = AbstractGraph::new(&template, level);
+            for (i, start) in open.iter().enumerate().step_by(5) {
+                let goal = open[(i * 7 + 3) % open.len()];
+                let mut problem =
+                    Problem::new(&octree, Mode::Cells, *start, goal).with_occupancy(&occupancy);
+                let refined = graph.search(&mut problem);
+                assert_eq!(refined.fallback, None);
+                let path = refined.path.unwrap();
+                let cheapest = problem.search().unwrap();
+
+                assert_walkable(&path, &problem);
+                assert!(path.total_cost >= cheapest.total_cost - 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn occupied_ends_attach_to_the_clusters_around_them() {
+        //the start and goal cells hold points, as the picked points of the command line do
+        let octree = grid(
+            (0..63)
+                .map(|i| point(4.5, (i / 8) as f64 + 0.5, (i % 8) as f64 + 0.5))
+                .chain([point(0.5, 0.5, 0.5), point(7.5, 0.5, 0.5)]),
+        );
+        let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+        let start = cell(&octree, 0.5, 0.5, 0.5);
+        let goal = cell(&octree, 7.5, 0.5, 0.5);
+        assert!(!occupancy.is_traversable(start.start));
+        assert!(!occupancy.is_traversable(goal.start));
+
+        let mut problem =
+            Problem::new(&octree, Mode::Cells, start, goal).with_occupancy(&occupancy);
+        for level in 0..4 {
+            let graph = AbstractGraph::new(&problem, level);
+            let refined = graph.search(&mut problem);
+            assert_eq!(refined.fallback, None);
+            assert_walkable(&refined.path.unwrap(), &problem);
+        }
+
+        let is_goal = |state: State| state == goal;
+        let mut tested = Problem::new(&octree, Mode::Cells, start, goal)
+            .with_occupancy(&occupancy)
+            .with_goal_test(&is_goal);
+        let refined = AbstractGraph::new(&tested, 1).search(&mut tested);
+        assert_eq!(refined.fallback, Some(Fallback::Unsupported));
+        assert!(refined.path.is_some());
+    }
+
+    #[test]
+    fn graph_of_an_older_tree_is_not_used() {
+        let mut octree = unit_cells(8, [point(0.5, 0.5, 0.5), point(7.5, 0.5, 0.5)]);
+        let start = cell(&octree, 0.5, 0.5, 0.5);
+        let graph = AbstractGraph::new(&Problem::new(&octree, Mode::Cells, start, start), 1);
+
+        //filling adds leaves past the end of the clusters the graph knows
+        octree.fill_empty_octants();
+        let start = cell(&octree, 0.5, 0.5, 0.5);
+        let goal = cell(&octree, 7.5, 0.5, 0.5);
+        let mut problem = Problem::new(&octree, Mode::Cells, start, goal);
+        let refined = graph.search(&mut problem);
+
+        assert_eq!(refined.fallback, Some(Fallback::Stale));
+        assert_eq!(
+            refined.path.map(|path| path.total_cost),
+            problem.search().map(|path| path.total_cost)
+        );
+        assert_eq!(graph.cluster(NodeId(octree.nodes.len() - 1)), None);
+    }
+}
@@ -0,0 +1,12 @@
+pub mod a_star;
+pub mod cli;
+pub mod ground;
+pub mod hierarchy;
+pub mod model;
+pub mod occupancy;
+pub mod region;
+pub mod search;
+pub mod smoothing;
+
+#[cfg(test)]
+mod test_util;
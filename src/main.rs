@@ -1,77 +1,344 @@
-//Tam, kad paleisti programą, į aplanką kuriame yra aplankas src reikia
-//įkelti failą 2743_1234.las
+//Programa paleidžiama su komanda ir LAS failo keliu, pvz.:
+//cargo run --release -- path 2743_1234.las --start 15 --goal 1000
+//Visos komandos aprašytos cli::USAGE
 
-mod a_star;
-mod model;
+use las::{point::Classification, Point, Read, Reader, Vector};
+use std::{env, process};
 
-use las::{Bounds, Read, Reader, Point};
-use std::collections::LinkedList;
-
-use crate::{model::Octree, a_star::{State, Problem}};
+use lsa_octree_challenge::{
+    a_star::{Mode, Problem, State},
+    cli::{Args, Command, PointRef, QueryRegion, USAGE},
+    ground::GroundMap,
+    hierarchy::AbstractGraph,
+    model::{point_distance_squared, NodeId, Octree},
+    occupancy::{OccupancyConfig, OccupancyMap},
+    smoothing,
+};
 
 fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
     println!("Reading file...");
 
-    let init_bounds = get_init_bounds(Reader::from_path("2743_1234.las").unwrap());
+    let mut reader = open_reader(&args.input);
 
-    println!("bounds: {:?}", init_bounds);
+    let (mut point_a, mut goal_pickers) = match &args.command {
+        Command::Path {
+            start,
+            goals,
+            ground,
+            ..
+        } => (
+            Some(PointPicker::new(*start, ground.is_some())),
+            goals
+                .iter()
+                .map(|goal| PointPicker::new(*goal, ground.is_some()))
+                .collect(),
+        ),
+        _ => (None, Vec::new()),
+    };
 
-    println!("Finished");
+    let header_bounds = Octree::trusted_bounds(reader.header().bounds());
 
     println!("subdividing...");
 
-    let mut octree = Octree::new(init_bounds, 0);
+    let points = reader.points().enumerate().map(|(index, wrapped_point)| {
+        let point = match wrapped_point {
+            Ok(point) => point,
+            Err(err) => exit_with(&format!("could not read point {}: {}", index, err)),
+        };
+        if let Some(picker) = point_a.as_mut() {
+            picker.offer(index, &point);
+        }
+        for picker in &mut goal_pickers {
+            picker.offer(index, &point);
+        }
+        point
+    });
+
+    let octree = if args.stream {
+        Octree::from_stream(header_bounds, points, &args.config)
+    } else {
+        Octree::from_points(points, &args.config)
+    };
 
-    let mut point_a = None;
-    let mut point_b = None;
+    let octree = match octree {
+        Some(octree) => octree,
+        None => exit_with(&format!("{} has no points", args.input)),
+    };
 
-    let mut iterations = 0;
+    println!("Finished");
 
-    for wrapped_point in Reader::from_path("2743_1234.las").unwrap().points() {
-        let point = wrapped_point.unwrap();
-        if iterations == 15{
-            point_a = Some(point.clone());
+    match args.command {
+        Command::Build => {
+            println!("Inserted {} points", octree.get_point_count());
         }
-        else {
-            point_b = Some(point.clone());
+        Command::Stats => {
+            print_stats(&octree);
         }
-        octree.insert_point(point, 5);
-        iterations += 1;
-    }
+        Command::Query { region } => {
+            println!("Searching");
+            let found = match &region {
+                QueryRegion::Box(bounds) => octree.query(bounds).count(),
+                QueryRegion::Prism(prism) => octree.query(prism).count(),
+            };
+            println!("Found {} points in {:?}", found, region);
+        }
+        Command::Nearest { at, k } => {
+            println!("Searching");
+            for (point, distance) in octree.nearest(at, k) {
+                println!(
+                    "({}, {}, {}) at distance {}",
+                    point.x, point.y, point.z, distance
+                );
+            }
+        }
+        Command::Radius { at, radius } => {
+            println!("Searching");
+            let found = octree.within_radius(at, radius);
+            println!("Found {} points within {} of {:?}", found.len(), radius, at);
+        }
+        Command::Path {
+            start,
+            goals,
+            mode,
+            algorithm,
+            bidirectional,
+            coarse_depth,
+            smooth,
+            occupancy,
+            ground,
+        } => {
+            let point_a = match point_a.and_then(PointPicker::into_point) {
+                Some(point_a) => point_a,
+                None => exit_with(&format!("start point {:?} is not in the file", start)),
+            };
+            let goal_points: Vec<Point> = goals
+                .iter()
+                .zip(goal_pickers)
+                .map(|(goal, picker)| match picker.into_point() {
+                    Some(point) => point,
+                    None => exit_with(&format!("goal point {:?} is not in the file", goal)),
+                })
+                .collect();
 
-    println!("Finished");
+            let from = position(start, &point_a);
+            let tos: Vec<Vector<f64>> = goals
+                .iter()
+                .zip(&goal_points)
+                .map(|(goal, point)| position(*goal, point))
+                .collect();
 
-    println!("Searching");
+            //free space needs cells of its own before it can be classified and walked through
+            let mut octree = octree;
+            let occupancy = occupancy.map(|config| {
+                octree.fill_empty_octants();
+                //the picked points make the end cells occupied, they must not block the
+                //cells an agent leaves or reaches them through
+                let ends: Vec<NodeId> = std::iter::once(&from)
+                    .chain(&tos)
+                    .filter_map(|at| octree.locate(at.x, at.y, at.z))
+                    .collect();
+                let mut map = OccupancyMap::classify(
+                    &octree,
+                    &OccupancyConfig {
+                        agent_radius: 0.0,
+                        ..config.clone()
+                    },
+                );
+                map.inflate_except(&octree, config.agent_radius, &ends);
+                map
+            });
+            let ground = ground.map(|config| GroundMap::new(&octree, config));
 
-    //println!("point_a: {:?}, point_b: {:?}", point_a, point_b);
-    
-    let initial_state = State::new(point_a.unwrap(), &octree);
-    let goal_state = State::new(point_b.unwrap(), &octree);
+            println!("Searching");
 
-    //println!("initial_state: {:?}, goal_state: {:?}", initial_state, goal_state);
+            let state = |at: &Vector<f64>, point: &Point| {
+                if occupancy.is_some() {
+                    State::at(at.x, at.y, at.z, &octree)
+                } else {
+                    State::new(point, &octree)
+                }
+            };
+            let initial_state = state(&from, &point_a);
+            let goal_states: Option<Vec<State>> = tos
+                .iter()
+                .zip(&goal_points)
+                .map(|(to, point)| state(to, point))
+                .collect();
 
-    if let Some(initial_state) = initial_state {
-        if let Some(goal_state) = goal_state {
-            let mut prob = Problem::new(initial_state, goal_state);
-            println!("Path: {:?}", prob.search());
+            if let (Some(initial_state), Some(goal_states)) = (initial_state, goal_states) {
+                //a point given by index can be of any class, its cell may have no ground
+                if let Some(ground) = &ground {
+                    let ends = std::iter::once((start, initial_state))
+                        .chain(goals.iter().copied().zip(goal_states.iter().copied()));
+                    for (end, state) in ends {
+                        if ground.surface(state.start).is_none() {
+                            exit_with(&format!(
+                                "point {:?} has no ground points in its cell to walk from, \
+                                 give a ground point or coordinates",
+                                end
+                            ));
+                        }
+                    }
+                }
+                let mut prob = Problem::new(&octree, mode, initial_state, goal_states[0])
+                    .with_goals(goal_states.clone());
+                if let Some(occupancy) = &occupancy {
+                    prob = prob.with_occupancy(occupancy);
+                }
+                if let Some(ground) = &ground {
+                    prob = prob.with_ground(ground);
+                }
+                let path = if let Some(level) = coarse_depth {
+                    let graph = AbstractGraph::new(&prob, level);
+                    let refined = graph.search(&mut prob);
+                    if let Some(reason) = refined.fallback {
+                        println!("Searched all leaves instead of clusters: {}", reason);
+                    }
+                    refined.path
+                } else if bidirectional {
+                    let search = prob.search_bidirectional();
+                    println!(
+                        "Expanded {} Nodes forward, {} backward",
+                        search.forward_expanded, search.backward_expanded
+                    );
+                    search.path
+                } else {
+                    prob.search_with(algorithm)
+                };
+                match path {
+                    Some(path) if mode == Mode::Cells => {
+                        println!("Path cost: {}", path.total_cost);
+                        //the route ends at the point of the goal it reached
+                        let reached = goal_states
+                            .iter()
+                            .position(|goal| *goal == path.nodes[0].state)
+                            .unwrap_or(0);
+                        let mut waypoints = prob.waypoints(&path, from, tos[reached]);
+                        if smooth {
+                            match smoothing::smooth(&prob, &waypoints) {
+                                Ok(smoothed) => {
+                                    waypoints = smoothed;
+                                    println!("Smoothed length: {}", smoothing::length(&waypoints));
+                                }
+                                Err(blocked) => {
+                                    println!(
+                                        "Not smoothed, the route cuts closed space {:?}",
+                                        blocked
+                                    )
+                                }
+                            }
+                        }
+                        for waypoint in waypoints {
+                            println!("({}, {}, {})", waypoint.x, waypoint.y, waypoint.z);
+                        }
+                    }
+                    Some(path) => {
+                        println!("Path cost: {}", path.total_cost);
+                        for node in path.nodes.iter().rev() {
+                            if let Some(action) = &node.action {
+                                println!(
+                                    "{:?} -> {:?}",
+                                    octree[action.move_from].bounds, octree[action.move_to].bounds
+                                );
+                            }
+                        }
+                    }
+                    None => println!("No path found"),
+                }
+                println!("Explored {} Nodes", prob.nodes_visited);
+            } else {
+                exit_with("no node of the octree holds the start or a goal");
+            }
         }
     }
-    
+}
 
+//Picks the start or goal point out of the stream of points while the tree is being built,
+//either by its index in the file or as the point closest to the given coordinates. When
+//walking on the ground only ground points are closest, there is nothing to stand on elsewhere.
+struct PointPicker {
+    target: PointRef,
+    ground_only: bool,
+    best: Option<(f64, Point)>,
+}
 
+impl PointPicker {
+    fn new(target: PointRef, ground_only: bool) -> Self {
+        PointPicker {
+            target,
+            ground_only,
+            best: None,
+        }
+    }
 
+    fn offer(&mut self, index: usize, point: &Point) {
+        let distance = match self.target {
+            PointRef::Index(wanted) if wanted == index => 0.0,
+            PointRef::Index(_) => return,
+            PointRef::Coordinates(_)
+                if self.ground_only && point.classification != Classification::Ground =>
+            {
+                return
+            }
+            PointRef::Coordinates(at) => point_distance_squared(point, &at),
+        };
+        if self.best.as_ref().is_none_or(|(best, _)| distance < *best) {
+            self.best = Some((distance, point.clone()));
+        }
+    }
 
+    fn into_point(self) -> Option<Point> {
+        self.best.map(|(_, point)| point)
+    }
 }
 
-fn get_init_bounds(mut reader: Reader) -> Bounds {
-    let mut init_bounds = Bounds {
-        ..Default::default()
-    };
+//Where a path starts or ends: the given coordinates, or the picked point itself.
+fn position(target: PointRef, point: &Point) -> Vector<f64> {
+    match target {
+        PointRef::Coordinates(at) => at,
+        PointRef::Index(_) => Vector {
+            x: point.x,
+            y: point.y,
+            z: point.z,
+        },
+    }
+}
+
+fn print_stats(octree: &Octree) {
+    let node_count = octree.iter_nodes().count();
+    let leaf_count = octree
+        .iter_nodes()
+        .filter(|(_, node)| node.is_leaf())
+        .count();
+    let max_depth = octree
+        .iter_nodes()
+        .map(|(_, node)| node.depth)
+        .max()
+        .unwrap_or(0);
 
-    for wrapped_point in reader.points() {
-        let point = wrapped_point.unwrap();
-        init_bounds.grow(&point)
+    println!("bounds: {:?}", octree.bounds());
+    println!("points: {}", octree.get_point_count());
+    println!("nodes: {}", node_count);
+    println!("leaves: {}", leaf_count);
+    println!("depth: {}", max_depth);
+}
+
+fn open_reader(path: &str) -> Reader {
+    match Reader::from_path(path) {
+        Ok(reader) => reader,
+        Err(err) => exit_with(&format!("could not open {}: {}", path, err)),
     }
+}
 
-    init_bounds
+fn exit_with(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
 }
@@ -1,43 +1,115 @@
-use std::collections::LinkedList;
-
-use std::hash::Hash;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, LinkedList};
+use std::ops::{Index, IndexMut};
 
 use las::{Bounds, Point, Vector};
+
+use crate::region::{self, Containment};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub usize);
+
+//Path from the root to a node, three bits per level (the octant index) below a leading 1,
+//so the root is 1 and a child is its parent shifted up by one level. Fits MAX_MORTON_LEVELS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LocationalCode(pub u128);
+
+//How far apart two cells may touch to count as neighbours: 6, 18 or 26 directions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Adjacency {
+    Face,
+    Edge,
+    Vertex,
+}
+
+impl Adjacency {
+    //Steps of -1, 0 or 1 on each axis, a face step moves along one axis, an edge step
+    //along two and a vertex step along all three.
+    pub fn directions(self) -> impl Iterator<Item = [i32; 3]> {
+        let axes = match self {
+            Adjacency::Face => 1,
+            Adjacency::Edge => 2,
+            Adjacency::Vertex => 3,
+        };
+        (0..27)
+            .map(|i| [i % 3 - 1, i / 3 % 3 - 1, i / 9 - 1])
+            .filter(move |direction| {
+                let moved = direction.iter().filter(|step| **step != 0).count();
+                moved > 0 && moved <= axes
+            })
+    }
+}
+
 #[derive(Clone, Debug)]
-pub struct Octree {
+pub struct Node {
     pub depth: i32,
-    pub octants: Option<[Bounds; 8]>,
-    pub children: [Option<Box<Octree>>; 8],
+    pub octants: [Bounds; 8],
+    pub parent: Option<NodeId>,
+    pub octant: Option<usize>,
+    pub code: LocationalCode,
+    pub children: [Option<NodeId>; 8],
     pub points: Vec<Point>,
     pub bounds: Bounds,
 }
-#[derive(Debug)]
-enum SearchError{
-    NotFound
+
+//All nodes live in one vector and point at each other by index. The root is not always
+//the first node, growing the tree pushes the new root at the end.
+#[derive(Clone, Debug)]
+pub struct Octree {
+    pub nodes: Vec<Node>,
+    pub root: NodeId,
+}
+
+impl Index<NodeId> for Octree {
+    type Output = Node;
+
+    fn index(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
 }
 
-impl Hash for Octree {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.depth.hash(state);
-        self.children.hash(state);
+impl IndexMut<NodeId> for Octree {
+    fn index_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id.0]
     }
 }
 
+//Trees are equal when they have the same shape and the same points in every node, no
+//matter in which order their nodes are stored.
 impl PartialEq for Octree {
     fn eq(&self, other: &Self) -> bool {
-        self.depth == other.depth && self.octants == other.octants && self.children == other.children && self.points == other.points && self.bounds == other.bounds
+        self.same_subtree(self.root, other, other.root)
     }
 }
 
-impl Eq for Octree {
-    
+//A leaf is split once it holds more than max_points_per_leaf points, unless the
+//children would be deeper than max_depth or smaller than min_edge_length on every axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OctreeConfig {
+    pub max_depth: i32,
+    pub max_points_per_leaf: usize,
+    pub min_edge_length: f64,
 }
 
+impl Default for OctreeConfig {
+    fn default() -> Self {
+        OctreeConfig {
+            max_depth: 16,
+            max_points_per_leaf: 32,
+            min_edge_length: 0.1,
+        }
+    }
+}
+
+//Morton keys are stored in a u128, three bits per level.
+const MAX_MORTON_LEVELS: i32 = 42;
+
 pub trait Comparison {
     fn compare_area(&self, compare_to: Bounds) -> bool;
     fn contains_point(&self, point: &Point) -> bool;
     fn contains_area(&self, area: Bounds) -> bool;
     fn overlaps_area(&self, area: Bounds) -> bool;
+    fn distance_squared_to(&self, at: &Vector<f64>) -> f64;
 }
 
 impl Comparison for Bounds {
@@ -48,62 +120,243 @@ impl Comparison for Bounds {
             * (compare_to.max.y - compare_to.min.y)
             * (compare_to.max.z - compare_to.min.z);
 
-        if self_area > comparing_to_area {
-            true
-        } else {
-            false
-        }
+        self_area > comparing_to_area
     }
+
     fn contains_point(&self, point: &Point) -> bool {
-        if self.min.x <= point.x
+        self.min.x <= point.x
             && self.max.x >= point.x
             && self.min.y <= point.y
             && self.max.y >= point.y
             && self.min.z <= point.z
             && self.max.z >= point.z
-        {
-            true
-        } else {
-            false
-        }
     }
 
     fn contains_area(&self, area: Bounds) -> bool {
-        if self.min.x <= area.min.x
+        self.min.x <= area.min.x
             && self.max.x >= area.max.x
             && self.min.y <= area.min.y
             && self.max.y >= area.max.y
             && self.min.z <= area.min.z
             && self.max.z >= area.max.z
-        {
-            true
+    }
+
+    //Two boxes intersect unless some axis separates them, touching faces count as overlap.
+    fn overlaps_area(&self, area: Bounds) -> bool {
+        self.min.x <= area.max.x
+            && self.max.x >= area.min.x
+            && self.min.y <= area.max.y
+            && self.max.y >= area.min.y
+            && self.min.z <= area.max.z
+            && self.max.z >= area.min.z
+    }
+
+    //Zero when the point is inside the box.
+    fn distance_squared_to(&self, at: &Vector<f64>) -> f64 {
+        let dx = (self.min.x - at.x).max(0.0).max(at.x - self.max.x);
+        let dy = (self.min.y - at.y).max(0.0).max(at.y - self.max.y);
+        let dz = (self.min.z - at.z).max(0.0).max(at.z - self.max.z);
+        dx * dx + dy * dy + dz * dz
+    }
+}
+
+pub fn point_distance_squared(point: &Point, at: &Vector<f64>) -> f64 {
+    (point.x - at.x).powi(2) + (point.y - at.y).powi(2) + (point.z - at.z).powi(2)
+}
+
+//Entry of the nearest neighbour queue, either a whole node keyed by the distance to its
+//bounds or a single point. The heap is a max-heap, so the ordering is reversed.
+enum Candidate<'a> {
+    Node(&'a Node),
+    Point(&'a Point),
+}
+
+struct QueueEntry<'a> {
+    distance_squared: f64,
+    candidate: Candidate<'a>,
+}
+
+impl PartialEq for QueueEntry<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueueEntry<'_> {}
+
+impl PartialOrd for QueueEntry<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueueEntry<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance_squared
+            .total_cmp(&self.distance_squared)
+            //points before nodes at the same distance, so a point is returned as soon as
+            //nothing closer can be hiding in an unopened node
+            .then_with(|| match (&self.candidate, &other.candidate) {
+                (Candidate::Point(_), Candidate::Node(_)) => Ordering::Greater,
+                (Candidate::Node(_), Candidate::Point(_)) => Ordering::Less,
+                _ => Ordering::Equal,
+            })
+    }
+}
+
+impl LocationalCode {
+    pub const ROOT: LocationalCode = LocationalCode(1);
+
+    pub fn depth(self) -> i32 {
+        (127 - self.0.leading_zeros() as i32) / 3
+    }
+
+    pub fn child(self, octant: usize) -> Self {
+        debug_assert!(self.depth() < MAX_MORTON_LEVELS);
+        LocationalCode((self.0 << 3) | octant as u128)
+    }
+
+    pub fn parent(self) -> Option<Self> {
+        if self == LocationalCode::ROOT {
+            None
         } else {
-            false
+            Some(LocationalCode(self.0 >> 3))
         }
     }
 
-    fn overlaps_area(&self, area: Bounds) -> bool {
-        if (self.min.x >= area.min.x && self.min.x <= area.max.x)
-            || (self.max.x >= area.min.x && self.max.x <= area.max.x)
-            || (self.min.y >= area.min.y && self.min.y <= area.max.y)
-            || (self.max.y >= area.min.y && self.max.y <= area.max.y)
-            || (self.min.z >= area.min.z && self.min.z <= area.max.z)
-            || (self.max.z >= area.min.z && self.max.z <= area.max.z)
-        {
-            true
+    //Octant of the node within its parent, None for the root.
+    pub fn octant(self) -> Option<usize> {
+        self.parent().map(|_| (self.0 & 7) as usize)
+    }
+
+    //The code after a new root has been put above the old one, with the old root as the
+    //given octant of the new root.
+    pub fn under(self, octant: usize) -> Self {
+        debug_assert!(self.depth() < MAX_MORTON_LEVELS);
+        let shift = 3 * self.depth();
+        let top = 1u128 << shift;
+        LocationalCode((self.0 ^ top) | (((8 | octant) as u128) << shift))
+    }
+
+    //Cell index along x, y and z among the 2^depth cells of this depth.
+    pub fn coordinates(self) -> [u64; 3] {
+        let mut coordinates = [0; 3];
+        for level in (0..self.depth()).rev() {
+            let octant = (self.0 >> (3 * level)) & 7;
+            for (axis, coordinate) in coordinates.iter_mut().enumerate() {
+                *coordinate = (*coordinate << 1) | ((octant >> axis) & 1) as u64;
+            }
+        }
+        coordinates
+    }
+
+    pub fn from_coordinates(depth: i32, coordinates: [u64; 3]) -> Self {
+        let mut code = LocationalCode::ROOT;
+        for level in (0..depth).rev() {
+            let octant = (0..3)
+                .map(|axis| (((coordinates[axis] >> level) & 1) as usize) << axis)
+                .sum();
+            code = code.child(octant);
+        }
+        code
+    }
+
+    //Code of the cell of the same depth one step away, None when it is outside the root.
+    pub fn neighbour(self, direction: [i32; 3]) -> Option<Self> {
+        let depth = self.depth();
+        let cells = 1i64 << depth;
+        let mut coordinates = self.coordinates();
+        for (coordinate, step) in coordinates.iter_mut().zip(direction) {
+            let moved = *coordinate as i64 + step as i64;
+            if moved < 0 || moved >= cells {
+                return None;
+            }
+            *coordinate = moved as u64;
+        }
+        Some(LocationalCode::from_coordinates(depth, coordinates))
+    }
+
+    //True for the node itself too.
+    pub fn is_ancestor_of(self, other: LocationalCode) -> bool {
+        let levels = other.depth() - self.depth();
+        levels >= 0 && other.0 >> (3 * levels) == self.0
+    }
+}
+
+impl Node {
+    pub fn new(bounds: Bounds) -> Self {
+        Node {
+            depth: 0,
+            octants: Octree::octants_of(bounds),
+            parent: None,
+            octant: None,
+            code: LocationalCode::ROOT,
+            children: [None; 8],
+            points: Vec::new(),
+            bounds,
+        }
+    }
+
+    fn child(&self, id: NodeId, octant: usize) -> Self {
+        Node {
+            depth: self.depth + 1,
+            parent: Some(id),
+            octant: Some(octant),
+            code: self.code.child(octant),
+            ..Node::new(self.octants[octant])
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children.iter().all(Option::is_none)
+    }
+
+    pub fn centre(&self) -> Vector<f64> {
+        Vector {
+            x: (self.bounds.min.x + self.bounds.max.x) / 2.0,
+            y: (self.bounds.min.y + self.bounds.max.y) / 2.0,
+            z: (self.bounds.min.z + self.bounds.max.z) / 2.0,
+        }
+    }
+
+    fn can_split(&self, config: &OctreeConfig) -> bool {
+        let longest_child_edge = (self.bounds.max.x - self.bounds.min.x)
+            .max(self.bounds.max.y - self.bounds.min.y)
+            .max(self.bounds.max.z - self.bounds.min.z)
+            / 2.0;
+
+        self.depth + 1 < config.max_depth.min(MAX_MORTON_LEVELS + 1)
+            && longest_child_edge >= config.min_edge_length
+    }
+
+    fn octant_index(&self, point: &Point) -> Option<usize> {
+        if self.bounds.contains_point(point) {
+            Some(Octree::owning_octant(&self.octants, point))
         } else {
-            false
+            None
         }
     }
 }
 
 impl Octree {
-    pub fn new(bounds: Bounds, depth: i32) -> Self {
+    pub fn new(bounds: Bounds) -> Self {
+        Octree {
+            nodes: vec![Node::new(bounds)],
+            root: NodeId(0),
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        self[self.root].bounds
+    }
+
+    pub fn octants_of(bounds: Bounds) -> [Bounds; 8] {
         let half_of_x = bounds.min.x + ((bounds.max.x - bounds.min.x) / 2.0);
         let half_of_y = bounds.min.y + ((bounds.max.y - bounds.min.y) / 2.0);
         let half_of_z = bounds.min.z + ((bounds.max.z - bounds.min.z) / 2.0);
 
-        let octants = [
+        [
             Bounds {
                 //south west
                 min: bounds.min,
@@ -196,134 +449,1313 @@ impl Octree {
                 },
                 max: bounds.max,
             },
-        ];
+        ]
+    }
 
-        Octree {
-            depth: depth,
-            octants: Some(octants),
-            children: [None, None, None, None, None, None, None, None],
-            points: Vec::new(),
-            bounds: bounds,
+    //Builds the same tree as calling insert_point for every point in order, but sorts the
+    //points by their octant path (Morton key) first and then fills the tree from the sorted
+    //run, so every node is created once and its points end up next to each other.
+    pub fn from_points<I: IntoIterator<Item = Point>>(
+        points: I,
+        config: &OctreeConfig,
+    ) -> Option<Self> {
+        let mut bounds = Bounds::default();
+        let points: Vec<Point> = points
+            .into_iter()
+            .inspect(|point| bounds.grow(point))
+            .collect();
+
+        if points.is_empty() {
+            return None;
+        }
+
+        let mut octree = Octree::new(bounds);
+        let root = octree.root;
+
+        if points.len() <= config.max_points_per_leaf || !octree[root].can_split(config) {
+            octree[root].points = points;
+            return Some(octree);
         }
+
+        let levels = (config.max_depth - 1).clamp(0, MAX_MORTON_LEVELS);
+
+        let mut keyed: Vec<(Option<u128>, usize, Point)> = points
+            .into_iter()
+            .enumerate()
+            .map(|(index, point)| (Octree::morton_key(bounds, &point, levels), index, point))
+            .collect();
+        keyed.sort_by_key(|(key, _, _)| *key);
+
+        //points that are not inside any octant stay in the root, same as insert_point
+        let outside = keyed.iter().take_while(|(key, _, _)| key.is_none()).count();
+        let (keys, points): (Vec<_>, Vec<_>) = keyed
+            .into_iter()
+            .map(|(key, index, point)| (key.unwrap_or_default(), (index, point)))
+            .unzip();
+        let mut points = points.into_iter();
+
+        octree[root]
+            .points
+            .extend(points.by_ref().take(outside).map(|(_, point)| point));
+        octree.fill_children(root, &keys[outside..], &mut points, levels, 0, config);
+
+        Some(octree)
     }
 
-    pub fn get_point_count(&self) -> usize {
-        let mut point_count = self.points.len();
-        for child in &self.children {
-            match child {
-                Some(child) => point_count += child.get_point_count(),
-                None => {}
+    //Inserts the points one at a time as they are read, into a root with the header bounds
+    //when they can be trusted or else into a box around the first point, growing the root
+    //whenever a point falls outside it.
+    pub fn from_stream<I: IntoIterator<Item = Point>>(
+        header_bounds: Option<Bounds>,
+        points: I,
+        config: &OctreeConfig,
+    ) -> Option<Self> {
+        let mut octree = header_bounds.map(Octree::new);
+
+        for point in points {
+            let octree = octree.get_or_insert_with(|| Octree::new(Octree::bounds_around(&point)));
+            octree.grow_to_contain(&point, config);
+            octree.insert_point(point, config);
+        }
+
+        octree
+    }
+
+    fn morton_key(bounds: Bounds, point: &Point, levels: i32) -> Option<u128> {
+        let mut key = 0;
+        let mut bounds = bounds;
+
+        if !bounds.contains_point(point) {
+            return None;
+        }
+
+        for _ in 0..levels {
+            let octants = Octree::octants_of(bounds);
+            let octant = Octree::owning_octant(&octants, point);
+            key = (key << 3) | octant as u128;
+            bounds = octants[octant];
+        }
+
+        Some(key)
+    }
+
+    fn fill_sorted<I: Iterator<Item = (usize, Point)>>(
+        &mut self,
+        id: NodeId,
+        keys: &[u128],
+        points: &mut I,
+        levels: i32,
+        level: i32,
+        config: &OctreeConfig,
+    ) {
+        if level == levels
+            || keys.len() <= config.max_points_per_leaf
+            || !self[id].can_split(config)
+        {
+            //the keys go deeper than this leaf, so put its points back into file order
+            let mut leaf_points: Vec<_> = points.by_ref().take(keys.len()).collect();
+            leaf_points.sort_by_key(|(index, _)| *index);
+            self[id].points = leaf_points.into_iter().map(|(_, point)| point).collect();
+        } else {
+            self.fill_children(id, keys, points, levels, level, config);
+        }
+    }
+
+    fn fill_children<I: Iterator<Item = (usize, Point)>>(
+        &mut self,
+        id: NodeId,
+        keys: &[u128],
+        points: &mut I,
+        levels: i32,
+        level: i32,
+        config: &OctreeConfig,
+    ) {
+        let octant_of = |key: u128| ((key >> (3 * (levels - level - 1))) & 7) as usize;
+
+        let mut start = 0;
+        while start < keys.len() {
+            let octant = octant_of(keys[start]);
+            let end = start
+                + keys[start..]
+                    .iter()
+                    .take_while(|key| octant_of(**key) == octant)
+                    .count();
+
+            let child = self.child_or_create(id, octant);
+            self.fill_sorted(child, &keys[start..end], points, levels, level + 1, config);
+
+            start = end;
+        }
+    }
+
+    //Header bounds are only used as the root when they describe a real box,
+    //otherwise the root is started around the first point and grown as needed.
+    pub fn trusted_bounds(header_bounds: Bounds) -> Option<Bounds> {
+        let min = header_bounds.min;
+        let max = header_bounds.max;
+        let finite = [min.x, min.y, min.z, max.x, max.y, max.z]
+            .iter()
+            .all(|value| value.is_finite());
+
+        if finite && min.x < max.x && min.y < max.y && min.z < max.z {
+            Some(header_bounds)
+        } else {
+            None
+        }
+    }
+
+    pub fn bounds_around(point: &Point) -> Bounds {
+        Bounds {
+            min: Vector {
+                x: point.x,
+                y: point.y,
+                z: point.z,
+            },
+            max: Vector {
+                x: point.x + 1.0,
+                y: point.y + 1.0,
+                z: point.z + 1.0,
+            },
+        }
+    }
+
+    //Doubles the root towards the point until the point fits, the old root becomes
+    //one of the octants of the new root.
+    pub fn grow_to_contain(&mut self, point: &Point, config: &OctreeConfig) {
+        while !self.bounds().contains_point(point) {
+            let bounds = self.bounds();
+            let size_x = bounds.max.x - bounds.min.x;
+            let size_y = bounds.max.y - bounds.min.y;
+            let size_z = bounds.max.z - bounds.min.z;
+
+            let mut new_bounds = bounds;
+            let mut octant = 0;
+
+            if point.x < bounds.min.x {
+                new_bounds.min.x -= size_x;
+                octant += 1;
+            } else {
+                new_bounds.max.x += size_x;
+            }
+            if point.y < bounds.min.y {
+                new_bounds.min.y -= size_y;
+                octant += 2;
+            } else {
+                new_bounds.max.y += size_y;
+            }
+            if point.z < bounds.min.z {
+                new_bounds.min.z -= size_z;
+                octant += 4;
+            } else {
+                new_bounds.max.z += size_z;
+            }
+
+            for node in &mut self.nodes {
+                node.depth += 1;
+                node.code = node.code.under(octant);
+            }
+
+            let old_root = self.root;
+            let new_root = NodeId(self.nodes.len());
+            let mut node = Node::new(new_bounds);
+            node.children[octant] = Some(old_root);
+            self.nodes.push(node);
+            self[old_root].parent = Some(new_root);
+            self[old_root].octant = Some(octant);
+            self.root = new_root;
+
+            //the max faces of the old root can be split planes of the new root now, the
+            //points on them belong to the octants above and are inserted again
+            let octants = self[new_root].octants;
+            let owned = |point: &Point| {
+                new_bounds.contains_point(point) && Octree::owning_octant(&octants, point) == octant
+            };
+            let subtree: Vec<NodeId> = self.iter_subtree(old_root).map(|(id, _)| id).collect();
+            let mut moved = Vec::new();
+            for id in subtree {
+                let (kept, other): (Vec<Point>, Vec<Point>) = std::mem::take(&mut self[id].points)
+                    .into_iter()
+                    .partition(owned);
+                self[id].points = kept;
+                moved.extend(other);
+            }
+            for point in moved {
+                self.insert_point(point, config);
             }
         }
-        point_count
     }
 
+    //Creates the missing children of every inner node as empty leaves, so the leaves cover
+    //the whole root and empty space becomes cells that can be walked through.
+    pub fn fill_empty_octants(&mut self) {
+        let inner: Vec<NodeId> = self
+            .iter_nodes()
+            .filter(|(_, node)| !node.is_leaf())
+            .map(|(id, _)| id)
+            .collect();
+
+        for id in inner {
+            for octant in 0..8 {
+                self.child_or_create(id, octant);
+            }
+        }
+    }
+
+    pub fn get_point_count(&self) -> usize {
+        self.nodes.iter().map(|node| node.points.len()).sum()
+    }
+
+    //Every node of the tree, parents before their children.
+    pub fn iter_nodes(&self) -> Nodes<'_> {
+        self.iter_subtree(self.root)
+    }
+
+    //The node and everything below it, parents before their children.
+    pub fn iter_subtree(&self, id: NodeId) -> Nodes<'_> {
+        Nodes {
+            octree: self,
+            stack: vec![id],
+        }
+    }
+
+    //Every point of the tree, borrowed.
+    pub fn iter(&self) -> impl Iterator<Item = &Point> {
+        self.nodes.iter().flat_map(|node| node.points.iter())
+    }
+
+    //Points inside the region, borrowed and produced lazily while the tree is walked.
+    pub fn query<R: region::Region>(&self, region: R) -> Query<'_, R> {
+        Query {
+            octree: self,
+            region,
+            stack: vec![(self.root, false)],
+            current: None,
+        }
+    }
+
+    //Every point of the tree collected into a vector, iter borrows them lazily instead.
     pub fn get_all_points(&self) -> Vec<&Point> {
-        let mut output = Vec::new();
+        self.iter().collect()
+    }
+
+    //Appends the points inside the region to the list, query does the walk.
+    pub fn search<R: region::Region>(&self, region: R, list: &mut LinkedList<Point>) {
+        list.extend(self.query(region).cloned());
+    }
+
+    //Octants are half-open: a point on a split plane belongs to the octant above it, so
+    //only the cells on the max faces of the root keep their max side closed.
+    pub fn owning_octant(octants: &[Bounds; 8], point: &Point) -> usize {
+        Octree::octant_at(octants, point.x, point.y, point.z)
+    }
 
-        for point in &self.points {
-            output.push(point);
+    fn octant_at(octants: &[Bounds; 8], x: f64, y: f64, z: f64) -> usize {
+        let centre = octants[7].min;
+        let mut octant = 0;
+        if x >= centre.x {
+            octant += 1;
+        }
+        if y >= centre.y {
+            octant += 2;
         }
+        if z >= centre.z {
+            octant += 4;
+        }
+        octant
+    }
 
-        for child in &self.children {
-            if let Some(child) = child {
-                for point in child.get_all_points() {
-                    output.push(point);
-                }
+    //Leaf cell that owns the coordinate, None when it is outside the root or when the
+    //cell was never created because no point fell into it.
+    pub fn locate(&self, x: f64, y: f64, z: f64) -> Option<NodeId> {
+        self.locate_deepest(x, y, z)
+            .filter(|id| self[*id].is_leaf())
+    }
+
+    //Deepest existing node on the way down to the coordinate, which is the leaf itself or
+    //the node whose child in that direction is missing. None outside the root.
+    pub fn locate_deepest(&self, x: f64, y: f64, z: f64) -> Option<NodeId> {
+        let bounds = self.bounds();
+        let inside = bounds.min.x <= x
+            && x <= bounds.max.x
+            && bounds.min.y <= y
+            && y <= bounds.max.y
+            && bounds.min.z <= z
+            && z <= bounds.max.z;
+
+        if !inside {
+            return None;
+        }
+
+        let mut id = self.root;
+        loop {
+            let node = &self[id];
+            match node.children[Octree::octant_at(&node.octants, x, y, z)] {
+                Some(child) => id = child,
+                None => return Some(id),
             }
         }
-        output
     }
 
-    pub fn insert_point(&mut self, point: Point, max_depth: i32) {
-        for i in 0..8 {
-            if self.octants.unwrap()[i].contains_point(&point) {
-                if self.depth + 1 < max_depth {
-                    if let None = self.children[i] {
-                        self.children[i] = Some(Box::new(Octree::new(
-                            self.octants.unwrap()[i],
-                            self.depth + 1,
-                        )));
-                    }
-                    self.children[i]
-                        .as_mut()
-                        .unwrap()
-                        .insert_point(point, max_depth);
+    pub fn insert_point(&mut self, point: Point, config: &OctreeConfig) {
+        self.insert_below(self.root, point, config);
+    }
+
+    fn insert_below(&mut self, id: NodeId, point: Point, config: &OctreeConfig) {
+        let mut id = id;
+        loop {
+            if self[id].is_leaf() {
+                let node = &mut self[id];
+                node.points.push(point);
+                if node.points.len() > config.max_points_per_leaf && node.can_split(config) {
+                    self.split(id, config);
+                }
+                return;
+            }
+
+            match self[id].octant_index(&point) {
+                Some(i) => id = self.child_or_create(id, i),
+                None => {
+                    self[id].points.push(point);
                     return;
                 }
             }
         }
-        self.points.push(point);
     }
 
-    pub fn search_for_octant(&self, query: &Point) -> Option<Box<Octree>> {
-       
-        
+    fn split(&mut self, id: NodeId, config: &OctreeConfig) {
+        for point in std::mem::take(&mut self[id].points) {
+            match self[id].octant_index(&point) {
+                Some(i) => {
+                    let child = self.child_or_create(id, i);
+                    self.insert_below(child, point, config);
+                }
+                None => self[id].points.push(point),
+            }
+        }
+    }
+
+    fn child_or_create(&mut self, id: NodeId, i: usize) -> NodeId {
+        if let Some(child) = self[id].children[i] {
+            return child;
+        }
 
+        let child = NodeId(self.nodes.len());
+        let node = self[id].child(id, i);
+        self.nodes.push(node);
+        self[id].children[i] = Some(child);
+        child
+    }
 
-        if self.points.contains(query) {
-            println!("runs");
-            return Some(Box::new(self.clone()));
+    //Node that stores the point. Points outside the root bounds can only be in the root.
+    pub fn search_for_octant(&self, query: &Point) -> Option<NodeId> {
+        let id = self
+            .locate_deepest(query.x, query.y, query.z)
+            .unwrap_or(self.root);
+
+        if self[id].points.contains(query) {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    pub fn find_parent(&self, of: NodeId) -> Option<NodeId> {
+        self[of].parent
+    }
+
+    //The other existing children of the node's parent.
+    pub fn siblings(&self, of: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        self[of]
+            .parent
+            .into_iter()
+            .flat_map(move |parent| self[parent].children.iter().flatten().copied())
+            .filter(move |sibling| *sibling != of)
+    }
+
+    pub fn sibling(&self, of: NodeId, octant: usize) -> Option<NodeId> {
+        self[self[of].parent?].children[octant]
+    }
+
+    //Node with exactly this code, None when that cell was never created.
+    pub fn find_code(&self, code: LocationalCode) -> Option<NodeId> {
+        let id = self.deepest_on_path(code);
+        if self[id].code == code {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    fn deepest_on_path(&self, code: LocationalCode) -> NodeId {
+        let mut id = self.root;
+        for level in (0..code.depth()).rev() {
+            let octant = ((code.0 >> (3 * level)) & 7) as usize;
+            match self[id].children[octant] {
+                Some(child) => id = child,
+                None => break,
+            }
         }
-        else {
-            for child in &self.children {
+        id
+    }
+
+    //Leaves touching the node across all faces, edges or corners allowed by adjacency,
+    //bigger and smaller ones included.
+    pub fn neighbours(&self, id: NodeId, adjacency: Adjacency) -> Vec<NodeId> {
+        let mut result: Vec<NodeId> = adjacency
+            .directions()
+            .flat_map(|direction| self.neighbours_towards(id, direction))
+            .collect();
+        //a bigger leaf can touch along several directions
+        result.sort();
+        result.dedup();
+        result
+    }
+
+    //Leaves touching the node in one direction. The cell of the same size next to the node is
+    //found by locational code arithmetic, then either a bigger leaf covers it or its leaves
+    //on the side facing the node are collected.
+    pub fn neighbours_towards(&self, id: NodeId, direction: [i32; 3]) -> Vec<NodeId> {
+        let Some(code) = self[id].code.neighbour(direction) else {
+            return Vec::new();
+        };
+
+        let found = self.deepest_on_path(code);
+        if self[found].code != code {
+            //the cell was never created, unless a bigger leaf holds it, it is empty space
+            return if self[found].is_leaf() {
+                vec![found]
+            } else {
+                Vec::new()
+            };
+        }
+
+        let facing = |octant: usize| {
+            (0..3).all(|axis| {
+                let upper = (octant >> axis) & 1 == 1;
+                match direction[axis] {
+                    1 => !upper,
+                    -1 => upper,
+                    _ => true,
+                }
+            })
+        };
+
+        let mut result = Vec::new();
+        let mut stack = vec![found];
+        while let Some(id) = stack.pop() {
+            let node = &self[id];
+            if node.is_leaf() {
+                result.push(id);
+                continue;
+            }
+            for (octant, child) in node.children.iter().enumerate() {
                 if let Some(child) = child {
-                    if let Some(result) = child.search_for_octant(query) {
-                        return Some(result);
+                    if facing(octant) {
+                        stack.push(*child);
                     }
                 }
             }
-            return None
         }
+        result
+    }
+
+    //True when the node is the other node or lies above it.
+    pub fn is_ancestor(&self, ancestor: NodeId, of: NodeId) -> bool {
+        self[ancestor].code.is_ancestor_of(self[of].code)
+    }
 
+    fn same_subtree(&self, id: NodeId, other: &Octree, other_id: NodeId) -> bool {
+        let (node, other_node) = (&self[id], &other[other_id]);
 
-        
+        node.depth == other_node.depth
+            && node.bounds == other_node.bounds
+            && node.points == other_node.points
+            && node
+                .children
+                .iter()
+                .zip(&other_node.children)
+                .all(|pair| match pair {
+                    (Some(child), Some(other_child)) => {
+                        self.same_subtree(*child, other, *other_child)
+                    }
+                    (None, None) => true,
+                    _ => false,
+                })
     }
-        
-        
-    
 
-    pub fn equals(&self, comparing_to: &Box<Octree>) -> bool {
-        if self.depth == comparing_to.depth && self.bounds == comparing_to.bounds {
-            true
-        } else {
-            false
+    //Best-first search over nodes and points ordered by distance, returns up to k points
+    //closest to the query together with their distance, nearest first.
+    pub fn nearest(&self, query: Vector<f64>, k: usize) -> Vec<(&Point, f64)> {
+        let mut result = Vec::with_capacity(k);
+        let mut queue = BinaryHeap::new();
+
+        if k == 0 {
+            return result;
         }
+
+        queue.push(QueueEntry {
+            distance_squared: self.bounds().distance_squared_to(&query),
+            candidate: Candidate::Node(&self[self.root]),
+        });
+
+        while let Some(entry) = queue.pop() {
+            match entry.candidate {
+                Candidate::Point(point) => {
+                    result.push((point, entry.distance_squared.sqrt()));
+                    if result.len() == k {
+                        break;
+                    }
+                }
+                Candidate::Node(node) => {
+                    for point in &node.points {
+                        queue.push(QueueEntry {
+                            distance_squared: point_distance_squared(point, &query),
+                            candidate: Candidate::Point(point),
+                        });
+                    }
+                    for child in node.children.iter().flatten() {
+                        let child = &self[*child];
+                        queue.push(QueueEntry {
+                            distance_squared: child.bounds.distance_squared_to(&query),
+                            candidate: Candidate::Node(child),
+                        });
+                    }
+                }
+            }
+        }
+
+        result
     }
 
-    pub fn find_parent(&self, of: &Octree) -> Option<Box<Octree>> {
-        if self.bounds.contains_area(of.bounds) && self.depth == of.depth - 1 {
-            return Some(Box::new(self.clone()));
-        } else {
-            for child in &self.children {
-                if let Some(child) = child {
-                    if let Some(parent) = child.find_parent(of){
-                        return Some(parent);
+    //All points within radius of the centre. Nodes whose bounds are further away than the
+    //radius are skipped, nodes that fit inside the sphere are taken without testing points.
+    pub fn within_radius(&self, center: Vector<f64>, radius: f64) -> Vec<&Point> {
+        let mut result = Vec::new();
+        let radius_squared = radius * radius;
+        let mut stack = vec![self.root];
+
+        while let Some(id) = stack.pop() {
+            let node = &self[id];
+            if node.bounds.distance_squared_to(&center) > radius_squared {
+                continue;
+            }
+            if Octree::farthest_corner_squared(&node.bounds, &center) <= radius_squared {
+                result.extend(
+                    self.iter_subtree(id)
+                        .flat_map(|(_, node)| node.points.iter()),
+                );
+                continue;
+            }
+            result.extend(
+                node.points
+                    .iter()
+                    .filter(|point| point_distance_squared(point, &center) <= radius_squared),
+            );
+            stack.extend(node.children.iter().flatten());
+        }
+
+        result
+    }
+
+    fn farthest_corner_squared(bounds: &Bounds, at: &Vector<f64>) -> f64 {
+        let dx = (at.x - bounds.min.x).abs().max((bounds.max.x - at.x).abs());
+        let dy = (at.y - bounds.min.y).abs().max((bounds.max.y - at.y).abs());
+        let dz = (at.z - bounds.min.z).abs().max((bounds.max.z - at.z).abs());
+        dx * dx + dy * dy + dz * dz
+    }
+}
+
+pub struct Nodes<'a> {
+    octree: &'a Octree,
+    stack: Vec<NodeId>,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = (NodeId, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.stack.pop()?;
+        let node = &self.octree[id];
+        self.stack.extend(node.children.iter().rev().flatten());
+        Some((id, node))
+    }
+}
+
+//Nodes on the stack are flagged when they are completely inside the query, then their
+//points and children are taken without testing them again.
+pub struct Query<'a, R> {
+    octree: &'a Octree,
+    region: R,
+    stack: Vec<(NodeId, bool)>,
+    current: Option<(std::slice::Iter<'a, Point>, bool)>,
+}
+
+impl<'a, R: region::Region> Iterator for Query<'a, R> {
+    type Item = &'a Point;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((points, inside)) = self.current.as_mut() {
+                for point in points {
+                    if *inside || self.region.contains_point(point) {
+                        return Some(point);
                     }
                 }
+                self.current = None;
             }
+
+            let (id, inside) = self.stack.pop()?;
+            let node = &self.octree[id];
+            for child in node.children.iter().rev().flatten() {
+                if inside {
+                    self.stack.push((*child, true));
+                    continue;
+                }
+                match self.region.classify_box(&self.octree[*child].bounds) {
+                    Containment::Inside => self.stack.push((*child, true)),
+                    Containment::Partial => self.stack.push((*child, false)),
+                    Containment::Outside => {}
+                }
+            }
+            self.current = Some((node.points.iter(), inside));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{cube, Rng};
+
+    //Coordinates on a 1/4 grid inside 0..=16, so a lot of points land exactly on split
+    //planes and on the max faces of the root.
+    fn random_points(rng: &mut Rng, max_count: u64) -> Vec<Point> {
+        (0..1 + rng.below(max_count))
+            .map(|id| Point {
+                x: rng.quarter(65),
+                y: rng.quarter(65),
+                z: rng.quarter(65),
+                gps_time: Some(id as f64),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn random_config(rng: &mut Rng) -> OctreeConfig {
+        OctreeConfig {
+            max_depth: 1 + rng.below(8) as i32,
+            max_points_per_leaf: rng.below(6) as usize,
+            min_edge_length: [0.0, 0.5, 2.0][rng.below(3) as usize],
+        }
+    }
+
+    fn root_bounds() -> Bounds {
+        cube((0.0, 0.0, 0.0), (16.0, 16.0, 16.0))
+    }
+
+    fn random_query(rng: &mut Rng) -> Bounds {
+        let (x1, x2) = (rng.quarter(65), rng.quarter(65));
+        let (y1, y2) = (rng.quarter(65), rng.quarter(65));
+        let (z1, z2) = (rng.quarter(65), rng.quarter(65));
+        Bounds {
+            min: Vector {
+                x: x1.min(x2),
+                y: y1.min(y2),
+                z: z1.min(z2),
+            },
+            max: Vector {
+                x: x1.max(x2),
+                y: y1.max(y2),
+                z: z1.max(z2),
+            },
         }
-        None
     }
 
-    pub fn search(&mut self, query: Bounds, list: &mut LinkedList<Point>) {
-        for point in &self.points {
-            if query.contains_point(point) {
-                list.push_back(point.clone());
+    fn ids<'a, I: IntoIterator<Item = &'a Point>>(points: I) -> Vec<u64> {
+        let mut ids: Vec<u64> = points
+            .into_iter()
+            .map(|point| point.gps_time.unwrap() as u64)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn build(points: &[Point], config: &OctreeConfig) -> Octree {
+        let mut octree = Octree::new(root_bounds());
+        for point in points {
+            octree.insert_point(point.clone(), config);
+        }
+        octree
+    }
+
+    //Every stored point has to be inside its node with the max side open, except
+    //on the faces that the node shares with the root.
+    fn assert_owned(octree: &Octree) {
+        let root = octree.bounds();
+        for (_, node) in octree.iter_nodes() {
+            for point in &node.points {
+                let below_max = |value: f64, max: f64, root_max: f64| {
+                    value < max || (max == root_max && value == max)
+                };
+                assert!(node.bounds.contains_point(point));
+                assert!(below_max(point.x, node.bounds.max.x, root.max.x));
+                assert!(below_max(point.y, node.bounds.max.y, root.max.y));
+                assert!(below_max(point.z, node.bounds.max.z, root.max.z));
             }
         }
-        for child in &mut self.children {
-            match child {
-                Some(child) => {
-                    if query.contains_area(child.bounds) {
-                        for point in child.get_all_points() {
-                            list.push_back(point.clone());
-                        }
-                    } else if child.bounds.overlaps_area(query) {
-                        child.search(query, list);
+    }
+
+    #[test]
+    fn overlaps_area_needs_overlap_on_every_axis() {
+        let unit = cube((0.0, 0.0, 0.0), (1.0, 1.0, 1.0));
+
+        assert!(!unit.overlaps_area(cube((0.5, 5.0, 5.0), (2.0, 6.0, 6.0))));
+        assert!(!unit.overlaps_area(cube((0.5, 0.5, 5.0), (2.0, 2.0, 6.0))));
+        assert!(!unit.overlaps_area(cube((2.0, 2.0, 2.0), (3.0, 3.0, 3.0))));
+        assert!(unit.overlaps_area(cube((0.5, 0.5, 0.5), (2.0, 2.0, 2.0))));
+    }
+
+    #[test]
+    fn overlaps_area_handles_containment_both_ways() {
+        let unit = cube((0.0, 0.0, 0.0), (1.0, 1.0, 1.0));
+        let inner = cube((0.25, 0.25, 0.25), (0.75, 0.75, 0.75));
+        let outer = cube((-1.0, -1.0, -1.0), (2.0, 2.0, 2.0));
+
+        assert!(unit.overlaps_area(inner));
+        assert!(inner.overlaps_area(unit));
+        assert!(unit.overlaps_area(outer));
+        assert!(outer.overlaps_area(unit));
+    }
+
+    #[test]
+    fn overlaps_area_counts_touching_faces_edges_and_corners() {
+        let unit = cube((0.0, 0.0, 0.0), (1.0, 1.0, 1.0));
+
+        assert!(unit.overlaps_area(cube((1.0, 0.0, 0.0), (2.0, 1.0, 1.0))));
+        assert!(unit.overlaps_area(cube((1.0, 1.0, 0.0), (2.0, 2.0, 1.0))));
+        assert!(unit.overlaps_area(cube((1.0, 1.0, 1.0), (2.0, 2.0, 2.0))));
+        assert!(!unit.overlaps_area(cube((1.0 + 1e-9, 0.0, 0.0), (2.0, 1.0, 1.0))));
+    }
+
+    #[test]
+    fn overlaps_area_is_symmetric() {
+        let mut rng = Rng(0xA076_1D64_78BD_642F);
+        for _ in 0..1000 {
+            let a = random_query(&mut rng);
+            let b = random_query(&mut rng);
+            assert_eq!(a.overlaps_area(b), b.overlaps_area(a));
+        }
+    }
+
+    #[test]
+    fn points_on_split_planes_go_to_the_upper_octant() {
+        let octants = Octree::octants_of(root_bounds());
+        let on_centre = Point {
+            x: 8.0,
+            y: 8.0,
+            z: 8.0,
+            ..Default::default()
+        };
+        let on_x_plane = Point {
+            x: 8.0,
+            y: 1.0,
+            z: 1.0,
+            ..Default::default()
+        };
+        let on_root_max = Point {
+            x: 16.0,
+            y: 16.0,
+            z: 0.0,
+            ..Default::default()
+        };
+
+        assert_eq!(Octree::owning_octant(&octants, &on_centre), 7);
+        assert_eq!(Octree::owning_octant(&octants, &on_x_plane), 1);
+        assert_eq!(Octree::owning_octant(&octants, &on_root_max), 3);
+    }
+
+    #[test]
+    fn every_point_is_stored_exactly_once() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..50 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let octree = build(&points, &config);
+
+            assert_eq!(ids(octree.iter()), ids(&points));
+            assert_eq!(ids(octree.get_all_points()), ids(&points));
+            assert_owned(&octree);
+        }
+    }
+
+    #[test]
+    fn parent_and_child_links_agree() {
+        let mut rng = Rng(0x6A09_E667_F3BC_C908);
+        for _ in 0..20 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let mut octree = build(&points, &config);
+            octree.grow_to_contain(
+                &Point {
+                    x: -20.0,
+                    y: 40.0,
+                    z: -1.0,
+                    ..Default::default()
+                },
+                &config,
+            );
+
+            assert_eq!(ids(octree.iter()), ids(&points));
+            assert_owned(&octree);
+            for point in &points {
+                assert!(octree.search_for_octant(point).is_some());
+                assert!(octree.locate(point.x, point.y, point.z).is_some());
+            }
+
+            assert_eq!(octree.iter_nodes().count(), octree.nodes.len());
+            assert_eq!(octree[octree.root].parent, None);
+            assert_eq!(octree[octree.root].depth, 0);
+            assert_eq!(octree[octree.root].code, LocationalCode::ROOT);
+            for (id, node) in octree.iter_nodes() {
+                assert_eq!(node.code.depth(), node.depth);
+                assert_eq!(node.code.octant(), node.octant);
+                for (i, child) in node.children.iter().enumerate() {
+                    if let Some(child) = child {
+                        assert_eq!(octree[*child].parent, Some(id));
+                        assert_eq!(octree[*child].octant, Some(i));
+                        assert_eq!(octree[*child].code, node.code.child(i));
+                        assert_eq!(octree[*child].code.parent(), Some(node.code));
+                        assert_eq!(octree[*child].depth, node.depth + 1);
+                        assert_eq!(octree[*child].bounds, node.octants[i]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sibling_and_ancestor_queries_match_the_links() {
+        let mut rng = Rng(0xBB67_AE85_84CA_A73B);
+        for _ in 0..20 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let octree = build(&points, &config);
+
+            for (id, node) in octree.iter_nodes() {
+                let mut siblings: Vec<NodeId> = octree.siblings(id).collect();
+                siblings.sort();
+                let mut expected: Vec<NodeId> = match node.parent {
+                    Some(parent) => octree[parent].children.iter().flatten().copied().collect(),
+                    None => Vec::new(),
+                };
+                expected.retain(|other| *other != id);
+                expected.sort();
+                assert_eq!(siblings, expected);
+
+                if let Some(octant) = node.octant {
+                    assert_eq!(octree.sibling(id, octant), Some(id));
+                }
+
+                let mut ancestors = vec![id];
+                while let Some(parent) = octree.find_parent(*ancestors.last().unwrap()) {
+                    ancestors.push(parent);
+                }
+                for (other, _) in octree.iter_nodes() {
+                    assert_eq!(octree.is_ancestor(other, id), ancestors.contains(&other));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn search_for_octant_finds_every_point_once() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        for _ in 0..20 {
+            let points = random_points(&mut rng, 200);
+            let config = random_config(&mut rng);
+            let octree = build(&points, &config);
+
+            for point in &points {
+                let node = octree.search_for_octant(point).unwrap();
+                assert_eq!(
+                    octree[node].points.iter().filter(|p| *p == point).count(),
+                    1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn locate_descends_to_the_owning_cell() {
+        let mut rng = Rng(0x3C6E_F372_FE94_F82B);
+        for _ in 0..30 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let octree = build(&points, &config);
+
+            for point in &points {
+                let leaf = octree.locate(point.x, point.y, point.z).unwrap();
+                assert_eq!(Some(leaf), octree.search_for_octant(point));
+            }
+
+            for _ in 0..50 {
+                let (x, y, z) = (rng.quarter(65), rng.quarter(65), rng.quarter(65));
+                let deepest = octree.locate_deepest(x, y, z).unwrap();
+                let node = &octree[deepest];
+                let at = Point {
+                    x,
+                    y,
+                    z,
+                    ..Default::default()
+                };
+
+                assert!(node.bounds.contains_point(&at));
+                assert_eq!(
+                    node.children[Octree::owning_octant(&node.octants, &at)],
+                    None
+                );
+                assert_eq!(
+                    octree.locate(x, y, z),
+                    Some(deepest).filter(|_| node.is_leaf())
+                );
+            }
+
+            assert_eq!(octree.locate_deepest(-0.5, 8.0, 8.0), None);
+            assert_eq!(octree.locate(8.0, 8.0, 16.5), None);
+        }
+    }
+
+    #[test]
+    fn adjacency_has_6_18_and_26_directions() {
+        assert_eq!(Adjacency::Face.directions().count(), 6);
+        assert_eq!(Adjacency::Edge.directions().count(), 18);
+        assert_eq!(Adjacency::Vertex.directions().count(), 26);
+    }
+
+    #[test]
+    fn neighbours_match_brute_force() {
+        let mut rng = Rng(0xA54F_F53A_5F1D_36F1);
+        for _ in 0..30 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let octree = build(&points, &config);
+
+            for (id, node) in octree.iter_nodes() {
+                let touching = |other: &Node, axes: usize| {
+                    let shared = [
+                        (
+                            node.bounds.min.x,
+                            node.bounds.max.x,
+                            other.bounds.min.x,
+                            other.bounds.max.x,
+                        ),
+                        (
+                            node.bounds.min.y,
+                            node.bounds.max.y,
+                            other.bounds.min.y,
+                            other.bounds.max.y,
+                        ),
+                        (
+                            node.bounds.min.z,
+                            node.bounds.max.z,
+                            other.bounds.min.z,
+                            other.bounds.max.z,
+                        ),
+                    ];
+                    //axes on which the boxes only meet in a plane
+                    let meeting = shared
+                        .iter()
+                        .filter(|(min, max, other_min, other_max)| {
+                            max == other_min || min == other_max
+                        })
+                        .count();
+                    node.bounds.overlaps_area(other.bounds) && meeting <= axes
+                };
+
+                for (adjacency, axes) in [
+                    (Adjacency::Face, 1),
+                    (Adjacency::Edge, 2),
+                    (Adjacency::Vertex, 3),
+                ] {
+                    let mut expected: Vec<NodeId> = octree
+                        .iter_nodes()
+                        .filter(|(other, leaf)| {
+                            leaf.is_leaf()
+                                && !octree.is_ancestor(id, *other)
+                                && touching(leaf, axes)
+                        })
+                        .map(|(other, _)| other)
+                        .collect();
+                    expected.sort();
+
+                    assert_eq!(octree.neighbours(id, adjacency), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn query_finds_every_point_in_the_query_once() {
+        let mut rng = Rng(0xD1B5_4A32_D192_ED03);
+        for _ in 0..50 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let octree = build(&points, &config);
+
+            for _ in 0..20 {
+                let query = random_query(&mut rng);
+                let found = octree.query(query);
+                let mut listed = LinkedList::new();
+                octree.search(query, &mut listed);
+
+                let expected = points.iter().filter(|point| query.contains_point(point));
+                assert_eq!(ids(found), ids(expected.clone()));
+                assert_eq!(ids(&listed), ids(expected));
+            }
+
+            for point in &points {
+                let query = Bounds {
+                    min: Vector {
+                        x: point.x,
+                        y: point.y,
+                        z: point.z,
+                    },
+                    max: Vector {
+                        x: point.x,
+                        y: point.y,
+                        z: point.z,
+                    },
+                };
+                assert_eq!(octree.query(query).filter(|p| *p == point).count(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let mut rng = Rng(0xBF58_476D_1CE4_E5B9);
+        for _ in 0..30 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let octree = build(&points, &config);
+
+            let query = Vector {
+                x: rng.quarter(65) + 0.1,
+                y: rng.quarter(65) - 0.1,
+                z: rng.quarter(65),
+            };
+            let k = 1 + rng.below(20) as usize;
+
+            let mut expected: Vec<f64> = points
+                .iter()
+                .map(|point| point_distance_squared(point, &query).sqrt())
+                .collect();
+            expected.sort_by(f64::total_cmp);
+            expected.truncate(k);
+
+            let found: Vec<f64> = octree
+                .nearest(query, k)
+                .iter()
+                .map(|(_, distance)| *distance)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn within_radius_matches_brute_force() {
+        let mut rng = Rng(0xE703_7ED1_A0B4_28DB);
+        for _ in 0..30 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let octree = build(&points, &config);
+
+            let center = Vector {
+                x: rng.quarter(65),
+                y: rng.quarter(65),
+                z: rng.quarter(65),
+            };
+            let radius = rng.below(40) as f64 / 4.0;
+
+            let expected = points
+                .iter()
+                .filter(|point| point_distance_squared(point, &center) <= radius * radius);
+            assert_eq!(ids(octree.within_radius(center, radius)), ids(expected));
+        }
+    }
+
+    fn assert_query_matches<R: region::Region>(octree: &Octree, points: &[Point], region: R) {
+        let expected = points.iter().filter(|point| region.contains_point(point));
+        let mut listed = LinkedList::new();
+        octree.search(&region, &mut listed);
+        assert_eq!(ids(octree.query(&region)), ids(expected.clone()));
+        assert_eq!(ids(&listed), ids(expected));
+    }
+
+    #[test]
+    fn query_matches_brute_force_for_every_region() {
+        let mut rng = Rng(0x6A09_E667_F3BC_C908);
+        for _ in 0..30 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let octree = build(&points, &config);
+
+            let center = Vector {
+                x: rng.quarter(65),
+                y: rng.quarter(65),
+                z: rng.quarter(65),
+            };
+            let half_extents = Vector {
+                x: 1.0 + rng.quarter(65) / 2.0,
+                y: 1.0 + rng.quarter(65) / 2.0,
+                z: 1.0 + rng.quarter(65) / 2.0,
+            };
+            let heading = rng.below(628) as f64 / 100.0;
+            assert_query_matches(
+                &octree,
+                &points,
+                region::OrientedBox::upright(center, half_extents, heading),
+            );
+
+            assert_query_matches(
+                &octree,
+                &points,
+                region::Frustum::perspective(
+                    Vector {
+                        x: -2.0,
+                        y: rng.quarter(65),
+                        z: rng.quarter(65),
+                    },
+                    Vector {
+                        x: 1.0,
+                        y: rng.quarter(65) / 16.0 - 0.5,
+                        z: rng.quarter(65) / 16.0 - 0.5,
+                    },
+                    Vector {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 1.0,
+                    },
+                    0.3 + rng.below(10) as f64 / 10.0,
+                    1.5,
+                    1.0,
+                    4.0 + rng.quarter(65),
+                ),
+            );
+
+            let polygon = (0..3 + rng.below(5))
+                .map(|_| (rng.quarter(65), rng.quarter(65)))
+                .collect();
+            let (z1, z2) = (rng.quarter(65), rng.quarter(65));
+            assert_query_matches(
+                &octree,
+                &points,
+                region::PolygonPrism {
+                    polygon,
+                    min_z: z1.min(z2),
+                    max_z: z1.max(z2),
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn bulk_load_matches_repeated_insert() {
+        let mut rng = Rng(0x94D0_49BB_1331_11EB);
+        for _ in 0..50 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+
+            let bulk = Octree::from_points(points.clone(), &config).unwrap();
+            let mut incremental = Octree::new(bulk.bounds());
+            for point in points {
+                incremental.insert_point(point, &config);
+            }
+
+            assert!(bulk == incremental);
+        }
+    }
+
+    #[test]
+    fn streamed_tree_holds_the_same_points_as_the_bulk_load() {
+        let mut rng = Rng(0xD1B5_4A32_D192_ED03);
+        let flat = cube((0.0, 0.0, 0.0), (16.0, 16.0, 0.0));
+        assert_eq!(Octree::trusted_bounds(root_bounds()), Some(root_bounds()));
+        assert_eq!(Octree::trusted_bounds(flat), None);
+        assert_eq!(Octree::trusted_bounds(Bounds::default()), None);
+
+        for _ in 0..50 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let bulk = Octree::from_points(points.clone(), &config).unwrap();
+
+            for header in [root_bounds(), flat] {
+                let streamed =
+                    Octree::from_stream(Octree::trusted_bounds(header), points.clone(), &config)
+                        .unwrap();
+
+                assert_eq!(ids(streamed.iter()), ids(bulk.iter()));
+                assert_owned(&streamed);
+                for point in &points {
+                    let node = streamed.search_for_octant(point).unwrap();
+                    assert_eq!(streamed.locate(point.x, point.y, point.z), Some(node));
+                }
+            }
+        }
+        assert!(Octree::from_stream(None, Vec::new(), &OctreeConfig::default()).is_none());
+    }
+
+    #[test]
+    fn bulk_load_fits_the_root_to_the_points_and_keeps_file_order() {
+        let mut rng = Rng(0x8BB8_4B93_962E_ACC9);
+        assert!(Octree::from_points(Vec::new(), &OctreeConfig::default()).is_none());
+
+        for _ in 0..50 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+            let octree = Octree::from_points(points.clone(), &config).unwrap();
+
+            let bounds = octree.bounds();
+            let min = |axis: fn(&Point) -> f64| points.iter().map(axis).fold(f64::MAX, f64::min);
+            let max = |axis: fn(&Point) -> f64| points.iter().map(axis).fold(f64::MIN, f64::max);
+            assert_eq!(bounds.min.x, min(|point| point.x));
+            assert_eq!(bounds.min.y, min(|point| point.y));
+            assert_eq!(bounds.min.z, min(|point| point.z));
+            assert_eq!(bounds.max.x, max(|point| point.x));
+            assert_eq!(bounds.max.y, max(|point| point.y));
+            assert_eq!(bounds.max.z, max(|point| point.z));
+
+            for (_, node) in octree.iter_nodes() {
+                let order: Vec<f64> = node.points.iter().map(|p| p.gps_time.unwrap()).collect();
+                assert!(order.windows(2).all(|pair| pair[0] < pair[1]));
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_are_split_only_past_their_capacity() {
+        let mut rng = Rng(0x1F83_D9AB_FB41_BD6B);
+        for _ in 0..50 {
+            let points = random_points(&mut rng, 300);
+            let config = random_config(&mut rng);
+
+            for octree in [
+                build(&points, &config),
+                Octree::from_points(points.clone(), &config).unwrap(),
+            ] {
+                for (id, node) in octree.iter_nodes() {
+                    let held: usize = octree
+                        .iter_subtree(id)
+                        .map(|(_, node)| node.points.len())
+                        .sum();
+                    if node.is_leaf() {
+                        assert!(held <= config.max_points_per_leaf || !node.can_split(&config));
+                    } else {
+                        assert!(node.points.is_empty());
+                        assert!(held > config.max_points_per_leaf);
+                        assert!(node.can_split(&config));
                     }
                 }
-                None => {}
             }
         }
     }
@@ -0,0 +1,306 @@
+use las::{Bounds, Vector};
+
+use crate::model::{NodeId, Octree};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Occupancy {
+    Occupied,
+    Free,
+    Unknown,
+}
+
+//Leaves with at least min_points points are occupied. Without scanner positions every empty
+//leaf is taken as free, with them only the leaves a ray from a scanner to a point passes
+//through are free and the rest of the empty space stays unknown.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OccupancyConfig {
+    pub min_points: usize,
+    pub agent_radius: f64,
+    pub scanners: Vec<Vector<f64>>,
+}
+
+impl Default for OccupancyConfig {
+    fn default() -> Self {
+        OccupancyConfig {
+            min_points: 1,
+            agent_radius: 0.0,
+            scanners: Vec::new(),
+        }
+    }
+}
+
+//Occupancy of every node by NodeId, inner nodes are always unknown. Blocked cells are the
+//free ones closer to an occupied cell than the agent radius.
+#[derive(Clone, Debug)]
+pub struct OccupancyMap {
+    pub cells: Vec<Occupancy>,
+    pub blocked: Vec<bool>,
+}
+
+impl OccupancyMap {
+    //The tree should have its empty octants filled first, otherwise empty space has no
+    //leaves to mark.
+    pub fn classify(tree: &Octree, config: &OccupancyConfig) -> Self {
+        let mut cells = vec![Occupancy::Unknown; tree.nodes.len()];
+
+        for (id, node) in tree.iter_nodes().filter(|(_, node)| node.is_leaf()) {
+            cells[id.0] = if node.points.len() >= config.min_points.max(1) {
+                Occupancy::Occupied
+            } else if node.points.is_empty() && config.scanners.is_empty() {
+                Occupancy::Free
+            } else {
+                Occupancy::Unknown
+            };
+        }
+
+        for scanner in &config.scanners {
+            for point in tree.iter() {
+                let target = Vector {
+                    x: point.x,
+                    y: point.y,
+                    z: point.z,
+                };
+                for id in cells_on_ray(tree, scanner, &target) {
+                    if cells[id.0] == Occupancy::Unknown {
+                        cells[id.0] = Occupancy::Free;
+                    }
+                }
+            }
+        }
+
+        let mut map = OccupancyMap {
+            blocked: vec![false; cells.len()],
+            cells,
+        };
+        map.inflate(tree, config.agent_radius);
+        map
+    }
+
+    pub fn get(&self, id: NodeId) -> Occupancy {
+        self.cells[id.0]
+    }
+
+    pub fn is_traversable(&self, id: NodeId) -> bool {
+        self.cells[id.0] == Occupancy::Free && !self.blocked[id.0]
+    }
+
+    //Blocks every leaf that comes closer than radius to an occupied leaf, so an agent of that
+    //radius whose centre stays in the remaining free cells does not touch an obstacle.
+    pub fn inflate(&mut self, tree: &Octree, radius: f64) {
+        self.inflate_except(tree, radius, &[]);
+    }
+
+    //Inflation where the given occupied cells block nothing around them, so an agent that
+    //starts or ends in the cell of a picked point is not walled in by that cell itself.
+    pub fn inflate_except(&mut self, tree: &Octree, radius: f64, exempt: &[NodeId]) {
+        if radius <= 0.0 {
+            return;
+        }
+
+        let occupied: Vec<Bounds> = tree
+            .iter_nodes()
+            .filter(|(id, _)| self.cells[id.0] == Occupancy::Occupied && !exempt.contains(id))
+            .map(|(_, node)| node.bounds)
+            .collect();
+
+        for obstacle in occupied {
+            let mut stack = vec![tree.root];
+            while let Some(id) = stack.pop() {
+                let node = &tree[id];
+                if box_distance_squared(&node.bounds, &obstacle) >= radius * radius {
+                    continue;
+                }
+                if node.is_leaf() {
+                    self.blocked[id.0] = true;
+                }
+                stack.extend(node.children.iter().flatten());
+            }
+        }
+    }
+}
+
+//Leaves the segment passes through before it reaches the leaf holding its end, found by
+//stepping from leaf to leaf across the faces they are left through.
+fn cells_on_ray(tree: &Octree, from: &Vector<f64>, to: &Vector<f64>) -> Vec<NodeId> {
+    let direction = [to.x - from.x, to.y - from.y, to.z - from.z];
+    let at = |t: f64| {
+        [
+            from.x + direction[0] * t,
+            from.y + direction[1] * t,
+            from.z + direction[2] * t,
+        ]
+    };
+    let mut result = Vec::new();
+
+    let Some((mut t, end)) = clip(&tree.bounds(), from, &direction) else {
+        return result;
+    };
+    let target = tree.locate_deepest(to.x, to.y, to.z);
+
+    while t <= end {
+        let [x, y, z] = at(t);
+        let Some(id) = tree.locate_deepest(x, y, z) else {
+            break;
+        };
+        if Some(id) == target {
+            break;
+        }
+        if tree[id].is_leaf() {
+            result.push(id);
+        }
+        let exit = clip(&tree[id].bounds, from, &direction).map_or(t, |(_, exit)| exit);
+        //step just past the face so the next lookup lands in the next leaf
+        t = exit.max(t) + 1e-9;
+    }
+
+    result
+}
+
+//Range of the segment parameter, 0 at the start and 1 at the end, that lies inside the box.
+pub(crate) fn clip(
+    bounds: &Bounds,
+    from: &Vector<f64>,
+    direction: &[f64; 3],
+) -> Option<(f64, f64)> {
+    let mut enter = 0.0_f64;
+    let mut exit = 1.0_f64;
+    let axes = [
+        (from.x, bounds.min.x, bounds.max.x),
+        (from.y, bounds.min.y, bounds.max.y),
+        (from.z, bounds.min.z, bounds.max.z),
+    ];
+
+    for ((origin, min, max), step) in axes.into_iter().zip(direction) {
+        if *step == 0.0 {
+            if origin < min || origin > max {
+                return None;
+            }
+            continue;
+        }
+        let (a, b) = ((min - origin) / step, (max - origin) / step);
+        enter = enter.max(a.min(b));
+        exit = exit.min(a.max(b));
+    }
+
+    if enter <= exit {
+        Some((enter, exit))
+    } else {
+        None
+    }
+}
+
+fn box_distance_squared(a: &Bounds, b: &Bounds) -> f64 {
+    let gap = |a_min: f64, a_max: f64, b_min: f64, b_max: f64| {
+        (b_min - a_max).max(a_min - b_max).max(0.0)
+    };
+    let dx = gap(a.min.x, a.max.x, b.min.x, b.max.x);
+    let dy = gap(a.min.y, a.max.y, b.min.y, b.max.y);
+    let dz = gap(a.min.z, a.max.z, b.min.z, b.max.z);
+    dx * dx + dy * dy + dz * dz
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::a_star::{Mode, Problem, State};
+    use crate::test_util::{grid, point, vector};
+
+    fn cell(octree: &Octree, x: f64, y: f64, z: f64) -> NodeId {
+        octree.locate(x, y, z).unwrap()
+    }
+
+    #[test]
+    fn point_counts_split_occupied_free_and_unknown() {
+        let octree = grid([
+            point(0.5, 0.5, 0.5),
+            point(0.6, 0.6, 0.6),
+            point(4.5, 4.5, 4.5),
+        ]);
+        let config = OccupancyConfig {
+            min_points: 2,
+            ..Default::default()
+        };
+        let map = OccupancyMap::classify(&octree, &config);
+
+        assert_eq!(map.get(cell(&octree, 0.5, 0.5, 0.5)), Occupancy::Occupied);
+        assert_eq!(map.get(cell(&octree, 4.5, 4.5, 4.5)), Occupancy::Unknown);
+        assert_eq!(map.get(cell(&octree, 7.5, 0.5, 0.5)), Occupancy::Free);
+        for (id, node) in octree.iter_nodes().filter(|(_, node)| !node.is_leaf()) {
+            assert!(node.children.iter().all(Option::is_some));
+            assert_eq!(map.get(id), Occupancy::Unknown);
+        }
+    }
+
+    #[test]
+    fn rays_free_the_cells_between_scanner_and_point() {
+        let octree = grid([point(6.5, 0.5, 0.5)]);
+        let config = OccupancyConfig {
+            scanners: vec![vector(0.5, 0.5, 0.5)],
+            ..Default::default()
+        };
+        let map = OccupancyMap::classify(&octree, &config);
+
+        for x in 0..6 {
+            let id = cell(&octree, x as f64 + 0.5, 0.5, 0.5);
+            assert_eq!(map.get(id), Occupancy::Free);
+        }
+        assert_eq!(map.get(cell(&octree, 6.5, 0.5, 0.5)), Occupancy::Occupied);
+        assert_eq!(map.get(cell(&octree, 7.5, 0.5, 0.5)), Occupancy::Unknown);
+        assert_eq!(map.get(cell(&octree, 6.5, 1.5, 0.5)), Occupancy::Unknown);
+        assert_eq!(map.get(cell(&octree, 0.5, 6.5, 0.5)), Occupancy::Unknown);
+    }
+
+    #[test]
+    fn agent_radius_blocks_cells_near_obstacles() {
+        let octree = grid([point(4.5, 4.5, 4.5)]);
+        let obstacle = cell(&octree, 4.5, 4.5, 4.5);
+
+        let map = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+        assert!(map.blocked.iter().all(|blocked| !blocked));
+
+        let config = OccupancyConfig {
+            agent_radius: 1.0,
+            ..Default::default()
+        };
+        let map = OccupancyMap::classify(&octree, &config);
+
+        assert!(!map.is_traversable(obstacle));
+        for neighbour in octree.neighbours(obstacle, crate::model::Adjacency::Vertex) {
+            assert!(map.blocked[neighbour.0]);
+        }
+        assert!(map.is_traversable(cell(&octree, 6.5, 4.5, 4.5)));
+        assert!(map.is_traversable(cell(&octree, 7.5, 7.5, 7.5)));
+        //the empty 0..4 octant touches the obstacle in a corner
+        assert!(!map.is_traversable(cell(&octree, 0.5, 0.5, 0.5)));
+    }
+
+    #[test]
+    fn occupied_ends_are_not_walled_in_by_their_own_cells() {
+        let octree = grid([point(0.5, 0.5, 0.5), point(7.5, 0.5, 0.5)]);
+        let start = State::at(0.5, 0.5, 0.5, &octree).unwrap();
+        let goal = State::at(7.5, 0.5, 0.5, &octree).unwrap();
+        let config = OccupancyConfig {
+            agent_radius: 0.01,
+            ..Default::default()
+        };
+
+        let walled = OccupancyMap::classify(&octree, &config);
+        let mut problem = Problem::new(&octree, Mode::Cells, start, goal).with_occupancy(&walled);
+        assert!(problem.search().is_none());
+
+        let mut map = OccupancyMap::classify(
+            &octree,
+            &OccupancyConfig {
+                agent_radius: 0.0,
+                ..config.clone()
+            },
+        );
+        map.inflate_except(&octree, config.agent_radius, &[start.start, goal.start]);
+        let mut problem = Problem::new(&octree, Mode::Cells, start, goal).with_occupancy(&map);
+        let path = problem.search().unwrap();
+        assert!(path.total_cost >= 7.0);
+        assert!(path.nodes[1..path.nodes.len() - 1]
+            .iter()
+            .all(|node| map.is_traversable(node.state.start)));
+    }
+}
@@ -0,0 +1,578 @@
+use las::{Bounds, Point, Vector};
+
+use crate::model::Comparison;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Containment {
+    Inside,
+    Outside,
+    Partial,
+}
+
+//A region of space the octree can be queried with. classify_box may answer Partial for a
+//box that is really outside, that only costs a visit, but Inside and Outside must be exact.
+pub trait Region {
+    fn contains_point(&self, point: &Point) -> bool;
+    fn classify_box(&self, area: &Bounds) -> Containment;
+}
+
+impl Region for Bounds {
+    fn contains_point(&self, point: &Point) -> bool {
+        Comparison::contains_point(self, point)
+    }
+
+    fn classify_box(&self, area: &Bounds) -> Containment {
+        if self.contains_area(*area) {
+            Containment::Inside
+        } else if self.overlaps_area(*area) {
+            Containment::Partial
+        } else {
+            Containment::Outside
+        }
+    }
+}
+
+impl<R: Region> Region for &R {
+    fn contains_point(&self, point: &Point) -> bool {
+        (**self).contains_point(point)
+    }
+
+    fn classify_box(&self, area: &Bounds) -> Containment {
+        (**self).classify_box(area)
+    }
+}
+
+fn vector(x: f64, y: f64, z: f64) -> Vector<f64> {
+    Vector { x, y, z }
+}
+
+fn sub(a: &Vector<f64>, b: &Vector<f64>) -> Vector<f64> {
+    vector(a.x - b.x, a.y - b.y, a.z - b.z)
+}
+
+fn dot(a: &Vector<f64>, b: &Vector<f64>) -> f64 {
+    a.x * b.x + a.y * b.y + a.z * b.z
+}
+
+fn cross(a: &Vector<f64>, b: &Vector<f64>) -> Vector<f64> {
+    vector(
+        a.y * b.z - a.z * b.y,
+        a.z * b.x - a.x * b.z,
+        a.x * b.y - a.y * b.x,
+    )
+}
+
+fn normalize(a: &Vector<f64>) -> Vector<f64> {
+    let length = dot(a, a).sqrt();
+    vector(a.x / length, a.y / length, a.z / length)
+}
+
+fn point_vector(point: &Point) -> Vector<f64> {
+    vector(point.x, point.y, point.z)
+}
+
+fn box_corners(area: &Bounds) -> [Vector<f64>; 8] {
+    let mut corners = [area.min; 8];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        if i & 1 != 0 {
+            corner.x = area.max.x;
+        }
+        if i & 2 != 0 {
+            corner.y = area.max.y;
+        }
+        if i & 4 != 0 {
+            corner.z = area.max.z;
+        }
+    }
+    corners
+}
+
+fn project(corners: &[Vector<f64>; 8], axis: &Vector<f64>) -> (f64, f64) {
+    corners
+        .iter()
+        .map(|corner| dot(corner, axis))
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+            (min.min(value), max.max(value))
+        })
+}
+
+//A box rotated by three orthonormal axes around its centre.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OrientedBox {
+    pub center: Vector<f64>,
+    pub axes: [Vector<f64>; 3],
+    pub half_extents: Vector<f64>,
+}
+
+impl OrientedBox {
+    //Box standing upright and turned by heading radians around the z axis, the usual case
+    //for boxes drawn around buildings or vehicles.
+    pub fn upright(center: Vector<f64>, half_extents: Vector<f64>, heading: f64) -> Self {
+        let (sin, cos) = heading.sin_cos();
+        OrientedBox {
+            center,
+            axes: [
+                vector(cos, sin, 0.0),
+                vector(-sin, cos, 0.0),
+                vector(0.0, 0.0, 1.0),
+            ],
+            half_extents,
+        }
+    }
+
+    fn corners(&self) -> [Vector<f64>; 8] {
+        let mut corners = [self.center; 8];
+        let extents = [
+            self.half_extents.x,
+            self.half_extents.y,
+            self.half_extents.z,
+        ];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            for (bit, (axis, extent)) in self.axes.iter().zip(extents).enumerate() {
+                let sign = if i & (1 << bit) != 0 { 1.0 } else { -1.0 };
+                corner.x += axis.x * extent * sign;
+                corner.y += axis.y * extent * sign;
+                corner.z += axis.z * extent * sign;
+            }
+        }
+        corners
+    }
+
+    fn contains_vector(&self, at: &Vector<f64>) -> bool {
+        let offset = sub(at, &self.center);
+        let extents = [
+            self.half_extents.x,
+            self.half_extents.y,
+            self.half_extents.z,
+        ];
+        self.axes
+            .iter()
+            .zip(extents)
+            .all(|(axis, extent)| dot(&offset, axis).abs() <= extent)
+    }
+}
+
+impl Region for OrientedBox {
+    fn contains_point(&self, point: &Point) -> bool {
+        self.contains_vector(&point_vector(point))
+    }
+
+    fn classify_box(&self, area: &Bounds) -> Containment {
+        let box_corners = box_corners(area);
+
+        if box_corners
+            .iter()
+            .all(|corner| self.contains_vector(corner))
+        {
+            return Containment::Inside;
+        }
+
+        //separating axis test over the face normals of both boxes and their cross products
+        let own_corners = self.corners();
+        let world_axes = [
+            vector(1.0, 0.0, 0.0),
+            vector(0.0, 1.0, 0.0),
+            vector(0.0, 0.0, 1.0),
+        ];
+        let mut axes = Vec::with_capacity(15);
+        axes.extend(world_axes);
+        axes.extend(self.axes);
+        for world_axis in &world_axes {
+            for own_axis in &self.axes {
+                let axis = cross(world_axis, own_axis);
+                if dot(&axis, &axis) > 1e-12 {
+                    axes.push(axis);
+                }
+            }
+        }
+
+        let separated = axes.iter().any(|axis| {
+            let (box_min, box_max) = project(&box_corners, axis);
+            let (own_min, own_max) = project(&own_corners, axis);
+            box_max < own_min || own_max < box_min
+        });
+
+        if separated {
+            Containment::Outside
+        } else {
+            Containment::Partial
+        }
+    }
+}
+
+//Plane with the normal pointing into the region, a point is inside when
+//dot(normal, point) + offset >= 0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Plane {
+    pub normal: Vector<f64>,
+    pub offset: f64,
+}
+
+impl Plane {
+    pub fn through(point: &Vector<f64>, normal: &Vector<f64>) -> Self {
+        let normal = normalize(normal);
+        Plane {
+            normal,
+            offset: -dot(&normal, point),
+        }
+    }
+
+    fn distance(&self, at: &Vector<f64>) -> f64 {
+        dot(&self.normal, at) + self.offset
+    }
+}
+
+//Camera view volume bounded by the near, far and four side planes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    //Perspective camera at eye looking along direction, fov_y is the vertical field of view
+    //in radians and aspect is width over height.
+    pub fn perspective(
+        eye: Vector<f64>,
+        direction: Vector<f64>,
+        up: Vector<f64>,
+        fov_y: f64,
+        aspect: f64,
+        near: f64,
+        far: f64,
+    ) -> Self {
+        let forward = normalize(&direction);
+        let right = normalize(&cross(&forward, &up));
+        let up = cross(&right, &forward);
+
+        let half_height = (fov_y / 2.0).tan();
+        let half_width = half_height * aspect;
+
+        let along =
+            |scale: f64, axis: &Vector<f64>| vector(axis.x * scale, axis.y * scale, axis.z * scale);
+        let add = |a: &Vector<f64>, b: &Vector<f64>| vector(a.x + b.x, a.y + b.y, a.z + b.z);
+
+        //directions of the four side edges of the view volume
+        let right_side = add(&forward, &along(half_width, &right));
+        let left_side = add(&forward, &along(-half_width, &right));
+        let top_side = add(&forward, &along(half_height, &up));
+        let bottom_side = add(&forward, &along(-half_height, &up));
+
+        Frustum {
+            planes: [
+                Plane::through(&add(&eye, &along(near, &forward)), &forward),
+                Plane::through(&add(&eye, &along(far, &forward)), &along(-1.0, &forward)),
+                Plane::through(&eye, &cross(&left_side, &up)),
+                Plane::through(&eye, &cross(&up, &right_side)),
+                Plane::through(&eye, &cross(&top_side, &right)),
+                Plane::through(&eye, &cross(&right, &bottom_side)),
+            ],
+        }
+    }
+}
+
+impl Region for Frustum {
+    fn contains_point(&self, point: &Point) -> bool {
+        let at = point_vector(point);
+        self.planes.iter().all(|plane| plane.distance(&at) >= 0.0)
+    }
+
+    fn classify_box(&self, area: &Bounds) -> Containment {
+        let mut result = Containment::Inside;
+
+        for plane in &self.planes {
+            //corner furthest along the normal and the one furthest against it
+            let positive = vector(
+                if plane.normal.x >= 0.0 {
+                    area.max.x
+                } else {
+                    area.min.x
+                },
+                if plane.normal.y >= 0.0 {
+                    area.max.y
+                } else {
+                    area.min.y
+                },
+                if plane.normal.z >= 0.0 {
+                    area.max.z
+                } else {
+                    area.min.z
+                },
+            );
+            let negative = vector(
+                if plane.normal.x >= 0.0 {
+                    area.min.x
+                } else {
+                    area.max.x
+                },
+                if plane.normal.y >= 0.0 {
+                    area.min.y
+                } else {
+                    area.max.y
+                },
+                if plane.normal.z >= 0.0 {
+                    area.min.z
+                } else {
+                    area.max.z
+                },
+            );
+
+            if plane.distance(&positive) < 0.0 {
+                return Containment::Outside;
+            }
+            if plane.distance(&negative) < 0.0 {
+                result = Containment::Partial;
+            }
+        }
+
+        result
+    }
+}
+
+//Vertical prism made by extruding a 2D polygon (for example a parcel boundary) between
+//two heights. The polygon is a list of x, y vertices, closed implicitly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolygonPrism {
+    pub polygon: Vec<(f64, f64)>,
+    pub min_z: f64,
+    pub max_z: f64,
+}
+
+impl PolygonPrism {
+    fn edges(&self) -> impl Iterator<Item = ((f64, f64), (f64, f64))> + '_ {
+        self.polygon
+            .iter()
+            .zip(self.polygon.iter().cycle().skip(1))
+            .map(|(a, b)| (*a, *b))
+    }
+
+    //Even-odd rule, points exactly on an edge count as inside.
+    fn contains_xy(&self, x: f64, y: f64) -> bool {
+        let mut inside = false;
+        for ((x1, y1), (x2, y2)) in self.edges() {
+            if on_segment((x, y), (x1, y1), (x2, y2)) {
+                return true;
+            }
+            if (y1 > y) != (y2 > y) && x < x1 + (y - y1) * (x2 - x1) / (y2 - y1) {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+}
+
+fn on_segment(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> bool {
+    let cross = (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0);
+    cross == 0.0
+        && p.0 >= a.0.min(b.0)
+        && p.0 <= a.0.max(b.0)
+        && p.1 >= a.1.min(b.1)
+        && p.1 <= a.1.max(b.1)
+}
+
+//Liang-Barsky clipping of the segment against the rectangle, true when any part of the
+//segment is inside or on the rectangle.
+fn segment_touches_rectangle(a: (f64, f64), b: (f64, f64), area: &Bounds) -> bool {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let mut t_min: f64 = 0.0;
+    let mut t_max: f64 = 1.0;
+
+    for (p, q) in [
+        (-dx, a.0 - area.min.x),
+        (dx, area.max.x - a.0),
+        (-dy, a.1 - area.min.y),
+        (dy, area.max.y - a.1),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return false;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                t_min = t_min.max(t);
+            } else {
+                t_max = t_max.min(t);
+            }
+        }
+    }
+
+    t_min <= t_max
+}
+
+impl Region for PolygonPrism {
+    //A polygon needs three vertices to enclose anything, fewer make an empty prism.
+    fn contains_point(&self, point: &Point) -> bool {
+        self.polygon.len() >= 3
+            && point.z >= self.min_z
+            && point.z <= self.max_z
+            && self.contains_xy(point.x, point.y)
+    }
+
+    fn classify_box(&self, area: &Bounds) -> Containment {
+        if self.polygon.len() < 3 || area.max.z < self.min_z || area.min.z > self.max_z {
+            return Containment::Outside;
+        }
+
+        if self
+            .edges()
+            .any(|(a, b)| segment_touches_rectangle(a, b, area))
+        {
+            return Containment::Partial;
+        }
+
+        //no edge reaches the rectangle, so it is either wholly inside the polygon or wholly
+        //outside it
+        if self.contains_xy(area.min.x, area.min.y) {
+            if area.min.z >= self.min_z && area.max.z <= self.max_z {
+                Containment::Inside
+            } else {
+                Containment::Partial
+            }
+        } else {
+            Containment::Outside
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{cube, point};
+
+    #[test]
+    fn bounds_classify_boxes() {
+        let region = cube((0.0, 0.0, 0.0), (10.0, 10.0, 10.0));
+
+        assert_eq!(
+            region.classify_box(&cube((1.0, 1.0, 1.0), (2.0, 2.0, 2.0))),
+            Containment::Inside
+        );
+        assert_eq!(
+            region.classify_box(&cube((9.0, 9.0, 9.0), (12.0, 12.0, 12.0))),
+            Containment::Partial
+        );
+        assert_eq!(
+            region.classify_box(&cube((11.0, 0.0, 0.0), (12.0, 1.0, 1.0))),
+            Containment::Outside
+        );
+    }
+
+    #[test]
+    fn oriented_box_turned_by_45_degrees() {
+        let region = OrientedBox::upright(
+            vector(0.0, 0.0, 0.0),
+            vector(1.0, 1.0, 1.0),
+            std::f64::consts::FRAC_PI_4,
+        );
+
+        assert!(region.contains_point(&point(1.3, 0.0, 0.0)));
+        assert!(!region.contains_point(&point(0.9, 0.9, 0.0)));
+
+        assert_eq!(
+            region.classify_box(&cube((-0.5, -0.5, -0.5), (0.5, 0.5, 0.5))),
+            Containment::Inside
+        );
+        assert_eq!(
+            region.classify_box(&cube((1.0, -0.2, -0.2), (1.6, 0.2, 0.2))),
+            Containment::Partial
+        );
+        //inside the axis aligned bounds of the turned box, but past its diagonal face
+        assert_eq!(
+            region.classify_box(&cube((1.0, 1.0, -0.2), (1.4, 1.4, 0.2))),
+            Containment::Outside
+        );
+    }
+
+    #[test]
+    fn frustum_looking_along_x() {
+        let region = Frustum::perspective(
+            vector(0.0, 0.0, 0.0),
+            vector(1.0, 0.0, 0.0),
+            vector(0.0, 0.0, 1.0),
+            std::f64::consts::FRAC_PI_2,
+            1.0,
+            1.0,
+            10.0,
+        );
+
+        assert!(region.contains_point(&point(5.0, 4.0, -4.0)));
+        assert!(!region.contains_point(&point(5.0, 6.0, 0.0)));
+        assert!(!region.contains_point(&point(5.0, 0.0, 6.0)));
+        assert!(!region.contains_point(&point(0.5, 0.0, 0.0)));
+        assert!(!region.contains_point(&point(11.0, 0.0, 0.0)));
+        assert!(!region.contains_point(&point(-5.0, 0.0, 0.0)));
+
+        assert_eq!(
+            region.classify_box(&cube((4.0, -1.0, -1.0), (6.0, 1.0, 1.0))),
+            Containment::Inside
+        );
+        assert_eq!(
+            region.classify_box(&cube((8.0, -1.0, -1.0), (12.0, 1.0, 1.0))),
+            Containment::Partial
+        );
+        assert_eq!(
+            region.classify_box(&cube((-6.0, -1.0, -1.0), (-4.0, 1.0, 1.0))),
+            Containment::Outside
+        );
+    }
+
+    #[test]
+    fn prism_over_a_concave_parcel() {
+        //L shaped parcel, the square 5..10 x 5..10 is cut out of it
+        let region = PolygonPrism {
+            polygon: vec![
+                (0.0, 0.0),
+                (10.0, 0.0),
+                (10.0, 5.0),
+                (5.0, 5.0),
+                (5.0, 10.0),
+                (0.0, 10.0),
+            ],
+            min_z: 0.0,
+            max_z: 5.0,
+        };
+
+        assert!(region.contains_point(&point(2.0, 8.0, 1.0)));
+        assert!(region.contains_point(&point(10.0, 2.0, 1.0)));
+        assert!(!region.contains_point(&point(8.0, 8.0, 1.0)));
+        assert!(!region.contains_point(&point(2.0, 8.0, 6.0)));
+
+        assert_eq!(
+            region.classify_box(&cube((1.0, 1.0, 1.0), (4.0, 4.0, 4.0))),
+            Containment::Inside
+        );
+        assert_eq!(
+            region.classify_box(&cube((1.0, 1.0, 1.0), (4.0, 4.0, 8.0))),
+            Containment::Partial
+        );
+        assert_eq!(
+            region.classify_box(&cube((4.0, 4.0, 1.0), (6.0, 6.0, 4.0))),
+            Containment::Partial
+        );
+        assert_eq!(
+            region.classify_box(&cube((6.0, 6.0, 1.0), (9.0, 9.0, 4.0))),
+            Containment::Outside
+        );
+        assert_eq!(
+            region.classify_box(&cube((-5.0, -5.0, 1.0), (15.0, 15.0, 4.0))),
+            Containment::Partial
+        );
+    }
+
+    #[test]
+    fn prism_without_an_area_holds_nothing() {
+        for polygon in [vec![], vec![(0.0, 0.0)], vec![(0.0, 0.0), (10.0, 0.0)]] {
+            let region = PolygonPrism {
+                polygon,
+                min_z: 0.0,
+                max_z: 5.0,
+            };
+            assert!(!region.contains_point(&point(0.0, 0.0, 1.0)));
+            assert!(!region.contains_point(&point(5.0, 0.0, 1.0)));
+            assert_eq!(
+                region.classify_box(&cube((-1.0, -1.0, 0.0), (11.0, 1.0, 5.0))),
+                Containment::Outside
+            );
+        }
+    }
+}
@@ -0,0 +1,567 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::a_star::{ActionStatePair, Node, Path, State};
+
+//What the algorithms need to know about a problem: where it starts, the moves out of a
+//state, which states are goals and an estimate of the cost left from a state to a goal.
+pub trait SearchProblem {
+    fn start(&self) -> State;
+    fn successors(&self, state: State) -> Vec<ActionStatePair>;
+    fn is_goal(&self, state: State) -> bool;
+    fn heuristic(&self, state: State) -> f64;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    //f = g + h, the cheapest path when the heuristic never overestimates
+    AStar,
+    //f = g, the heuristic is not used
+    Dijkstra,
+    //f = h, few expansions but the path can be far from the cheapest
+    GreedyBestFirst,
+    //f = g + epsilon * h, the path costs at most epsilon times the cheapest one
+    WeightedAStar(f64),
+    //depth-first under a bound on f that grows each round, keeps only the current path
+    IdaStar,
+}
+
+//The path found, if any, with the nodes expanded and generated on the way, so runs of
+//different algorithms on the same problem can be compared.
+#[derive(Debug)]
+pub struct Search {
+    pub path: Option<Path>,
+    pub expanded: usize,
+    pub generated: usize,
+}
+
+impl Algorithm {
+    //reopen puts closed nodes back on the fringe when a cheaper way to them turns up, only
+    //needed when the heuristic is not consistent. IDA* has no closed set and ignores it.
+    pub fn run<P: SearchProblem>(self, problem: &P, reopen: bool) -> Search {
+        match self {
+            Algorithm::AStar => best_first(problem, 1.0, 1.0, reopen),
+            Algorithm::Dijkstra => best_first(problem, 1.0, 0.0, reopen),
+            Algorithm::GreedyBestFirst => best_first(problem, 0.0, 1.0, reopen),
+            Algorithm::WeightedAStar(epsilon) => best_first(problem, 1.0, epsilon, reopen),
+            Algorithm::IdaStar => ida_star(problem),
+        }
+    }
+}
+
+//Fringe entry with its g and f scores worked out once when it is pushed. The heap is a
+//max-heap, so the ordering is reversed: lowest f first and on equal f the highest g, which
+//is the node closest to the goal.
+struct FringeEntry {
+    f: f64,
+    g: f64,
+    index: usize,
+}
+
+impl PartialEq for FringeEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FringeEntry {}
+
+impl PartialOrd for FringeEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FringeEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .f
+            .total_cmp(&self.f)
+            .then_with(|| self.g.total_cmp(&other.g))
+    }
+}
+
+//One side of a best-first search: the nodes it created, which of them are closed and the
+//fringe of open ones.
+#[derive(Default)]
+pub(crate) struct Frontier {
+    //every node the search creates, parents point into it by index
+    pub nodes: Vec<Node>,
+    //nodes that have been expanded, by the same index
+    closed: Vec<bool>,
+    //index of the node created for each state
+    visited_nodes: HashMap<State, usize>,
+    //the open set is the fringe, a better g for a node pushes it again instead of
+    //moving it inside the heap, and the entry left behind is skipped when it comes out
+    fringe: BinaryHeap<FringeEntry>,
+    pub expanded: usize,
+    pub generated: usize,
+}
+
+impl Frontier {
+    pub fn new(start: State, f: f64) -> Self {
+        let mut frontier = Frontier::default();
+        frontier.add_start(start, f);
+        frontier
+    }
+
+    //Another state the side starts from, for searching from several at once.
+    pub fn add_start(&mut self, start: State, f: f64) {
+        if self.index(start).is_some() {
+            return;
+        }
+        self.nodes.push(Node {
+            state: start,
+            parent: None,
+            action: None,
+            g: 0.0,
+        });
+        self.closed.push(false);
+        self.visited_nodes.insert(start, self.nodes.len() - 1);
+        self.fringe.push(FringeEntry {
+            f,
+            g: 0.0,
+            index: self.nodes.len() - 1,
+        });
+        self.generated += 1;
+    }
+
+    pub fn index(&self, state: State) -> Option<usize> {
+        self.visited_nodes.get(&state).copied()
+    }
+
+    pub fn open(&self) -> usize {
+        self.fringe.len()
+    }
+
+    fn is_stale(&self, entry: &FringeEntry) -> bool {
+        self.closed[entry.index] || entry.g > self.nodes[entry.index].g
+    }
+
+    //Lowest f of the open nodes.
+    pub fn min_f(&mut self) -> Option<f64> {
+        while let Some(entry) = self.fringe.peek() {
+            if !self.is_stale(entry) {
+                return Some(entry.f);
+            }
+            self.fringe.pop();
+        }
+        None
+    }
+
+    //Closes the open node with the lowest f and returns its index.
+    pub fn pop(&mut self) -> Option<usize> {
+        while let Some(entry) = self.fringe.pop() {
+            if !self.is_stale(&entry) {
+                self.closed[entry.index] = true;
+                return Some(entry.index);
+            }
+        }
+        None
+    }
+
+    //Takes the move out of the node at index, returns the index of the node it leads to
+    //when that is new or got a better g. f scores the node for the fringe.
+    pub fn relax<F: Fn(&Node) -> f64>(
+        &mut self,
+        index: usize,
+        child: ActionStatePair,
+        f: F,
+        reopen: bool,
+    ) -> Option<usize> {
+        self.generated += 1;
+        let child_g = self.nodes[index].g + child.action.cost;
+
+        let child_index = match self.index(child.state) {
+            Some(last_seen) => {
+                if self.nodes[last_seen].g <= child_g || (self.closed[last_seen] && !reopen) {
+                    return None;
+                }
+                let last_seen_node = &mut self.nodes[last_seen];
+                last_seen_node.parent = Some(index);
+                last_seen_node.action = Some(child.action);
+                last_seen_node.g = child_g;
+                self.closed[last_seen] = false;
+                last_seen
+            }
+            None => {
+                self.nodes.push(Node {
+                    state: child.state,
+                    parent: Some(index),
+                    action: Some(child.action),
+                    g: child_g,
+                });
+                self.closed.push(false);
+                self.visited_nodes.insert(child.state, self.nodes.len() - 1);
+                self.nodes.len() - 1
+            }
+        };
+
+        self.fringe.push(FringeEntry {
+            f: f(&self.nodes[child_index]),
+            g: child_g,
+            index: child_index,
+        });
+        Some(child_index)
+    }
+}
+
+//Best-first search ordered by f = g_weight * g + h_weight * h.
+fn best_first<P: SearchProblem>(problem: &P, g_weight: f64, h_weight: f64, reopen: bool) -> Search {
+    let f = |node: &Node| g_weight * node.g + h_weight * problem.heuristic(node.state);
+    let start = problem.start();
+    let mut frontier = Frontier::new(start, h_weight * problem.heuristic(start));
+    let mut path = None;
+
+    while let Some(index) = frontier.pop() {
+        let state = frontier.nodes[index].state;
+        if problem.is_goal(state) {
+            path = Some(construct_path(&frontier.nodes, index));
+            break;
+        }
+        frontier.expanded += 1;
+
+        for child in problem.successors(state) {
+            frontier.relax(index, child, f, reopen);
+        }
+    }
+
+    Search {
+        path,
+        expanded: frontier.expanded,
+        generated: frontier.generated,
+    }
+}
+
+//Cheapest cost from the start to every state that can be reached, Dijkstra's algorithm
+//run until the fringe is empty. The parents of the nodes form a tree of cheapest paths.
+pub fn flood<P: SearchProblem>(problem: &P) -> Vec<Node> {
+    let mut frontier = Frontier::new(problem.start(), 0.0);
+    while let Some(index) = frontier.pop() {
+        frontier.expanded += 1;
+        for child in problem.successors(frontier.nodes[index].state) {
+            frontier.relax(index, child, |node: &Node| node.g, false);
+        }
+    }
+    frontier.nodes
+}
+
+fn ida_star<P: SearchProblem>(problem: &P) -> Search {
+    let start = problem.start();
+    let mut search = Search {
+        path: None,
+        expanded: 0,
+        generated: 1,
+    };
+    let mut path = vec![Node {
+        state: start,
+        parent: None,
+        action: None,
+        g: 0.0,
+    }];
+    let mut bound = problem.heuristic(start);
+
+    loop {
+        match deepen(problem, &mut path, bound, &mut search) {
+            Ok(()) => {
+                search.path = Some(construct_path(&path, path.len() - 1));
+                return search;
+            }
+            Err(next) if next.is_finite() => bound = next,
+            Err(_) => return search,
+        }
+    }
+}
+
+//Depth-first below the last node of the path, cutting off nodes whose f is over the bound.
+//Ok when the path has been extended to a goal, otherwise the smallest f that was cut off,
+//which is the bound for the next round.
+fn deepen<P: SearchProblem>(
+    problem: &P,
+    path: &mut Vec<Node>,
+    bound: f64,
+    search: &mut Search,
+) -> Result<(), f64> {
+    let index = path.len() - 1;
+    let (state, g) = (path[index].state, path[index].g);
+    let f = g + problem.heuristic(state);
+    if f > bound {
+        return Err(f);
+    }
+    if problem.is_goal(state) {
+        return Ok(());
+    }
+    search.expanded += 1;
+
+    let mut next = f64::INFINITY;
+    for child in problem.successors(state) {
+        //a state already on the path would only make a cycle
+        if path.iter().any(|node| node.state == child.state) {
+            continue;
+        }
+        search.generated += 1;
+        path.push(Node {
+            state: child.state,
+            parent: Some(index),
+            action: Some(child.action),
+            g: g + child.action.cost,
+        });
+        match deepen(problem, path, bound, search) {
+            Ok(()) => return Ok(()),
+            Err(cut_off) => next = next.min(cut_off),
+        }
+        path.pop();
+    }
+    Err(next)
+}
+
+pub(crate) fn construct_path(nodes: &[Node], goal: usize) -> Path {
+    let mut result = Path {
+        total_cost: nodes[goal].g,
+        nodes: Vec::new(),
+    };
+
+    let mut index = Some(goal);
+    while let Some(current) = index {
+        result.nodes.push(nodes[current].clone());
+        index = nodes[current].parent;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::a_star::Action;
+    use crate::model::NodeId;
+
+    //A grid of cells where entering a cell costs its weight, moves go to the four cells
+    //around and the heuristic is the Manhattan distance, which never overestimates while
+    //every weight is at least 1.
+    struct Grid {
+        width: usize,
+        weights: Vec<f64>,
+        start: usize,
+        goal: usize,
+    }
+
+    impl Grid {
+        fn new(rows: &[&str], start: usize, goal: usize) -> Self {
+            Grid {
+                width: rows[0].len(),
+                weights: rows
+                    .iter()
+                    .flat_map(|row| row.bytes().map(|cell| (cell - b'0') as f64))
+                    .collect(),
+                start,
+                goal,
+            }
+        }
+
+        fn state(index: usize) -> State {
+            State {
+                start: NodeId(index),
+            }
+        }
+    }
+
+    impl SearchProblem for Grid {
+        fn start(&self) -> State {
+            Grid::state(self.start)
+        }
+
+        fn successors(&self, state: State) -> Vec<ActionStatePair> {
+            let index = state.start.0;
+            let (x, y) = (index % self.width, index / self.width);
+            let height = self.weights.len() / self.width;
+            let mut next = Vec::new();
+            if x > 0 {
+                next.push(index - 1);
+            }
+            if x + 1 < self.width {
+                next.push(index + 1);
+            }
+            if y > 0 {
+                next.push(index - self.width);
+            }
+            if y + 1 < height {
+                next.push(index + self.width);
+            }
+            next.into_iter()
+                .filter(|next| self.weights[*next] > 0.0)
+                .map(|next| ActionStatePair {
+                    action: Action {
+                        move_to: NodeId(next),
+                        move_from: state.start,
+                        cost: self.weights[next],
+                    },
+                    state: Grid::state(next),
+                })
+                .collect()
+        }
+
+        fn is_goal(&self, state: State) -> bool {
+            state.start.0 == self.goal
+        }
+
+        fn heuristic(&self, state: State) -> f64 {
+            let (a, b) = (state.start.0, self.goal);
+            ((a % self.width).abs_diff(b % self.width) + (a / self.width).abs_diff(b / self.width))
+                as f64
+        }
+    }
+
+    //Start top left, goal bottom right, 0 is a wall. The way down the left side is
+    //expensive and the middle is a dead end, the cheap way goes along the top and down.
+    fn maze() -> Grid {
+        Grid::new(&["11111", "90101", "91101", "90011", "91111"], 0, 24)
+    }
+
+    //Nodes joined by one-way edges, with a heuristic given per node.
+    struct Graph {
+        edges: Vec<(usize, usize, f64)>,
+        heuristic: Vec<f64>,
+        goal: usize,
+    }
+
+    impl SearchProblem for Graph {
+        fn start(&self) -> State {
+            Grid::state(0)
+        }
+
+        fn successors(&self, state: State) -> Vec<ActionStatePair> {
+            self.edges
+                .iter()
+                .filter(|(from, _, _)| *from == state.start.0)
+                .map(|(from, to, cost)| ActionStatePair {
+                    action: Action {
+                        move_to: NodeId(*to),
+                        move_from: NodeId(*from),
+                        cost: *cost,
+                    },
+                    state: Grid::state(*to),
+                })
+                .collect()
+        }
+
+        fn is_goal(&self, state: State) -> bool {
+            state.start.0 == self.goal
+        }
+
+        fn heuristic(&self, state: State) -> f64 {
+            self.heuristic[state.start.0]
+        }
+    }
+
+    fn cost(search: &Search) -> f64 {
+        search.path.as_ref().unwrap().total_cost
+    }
+
+    #[test]
+    fn fringe_pops_lowest_f_then_highest_g() {
+        let entry = |f: f64, g: f64, index: usize| FringeEntry { f, g, index };
+        let mut fringe = BinaryHeap::from(vec![
+            entry(3.0, 1.0, 0),
+            entry(2.0, 0.5, 1),
+            entry(2.0, 1.5, 2),
+            entry(5.0, 0.0, 3),
+            entry(1.0, 1.0, 4),
+        ]);
+
+        let order: Vec<usize> = std::iter::from_fn(|| fringe.pop())
+            .map(|entry| entry.index)
+            .collect();
+        assert_eq!(order, vec![4, 2, 1, 0, 3]);
+    }
+
+    #[test]
+    fn optimal_algorithms_agree_on_the_cheapest_path() {
+        let grid = maze();
+        let cheapest = cost(&Algorithm::Dijkstra.run(&grid, true));
+        assert_eq!(cheapest, 8.0);
+
+        for algorithm in [Algorithm::AStar, Algorithm::IdaStar] {
+            let search = algorithm.run(&grid, true);
+            assert_eq!(cost(&search), cheapest);
+
+            let path = search.path.unwrap();
+            let actions: f64 = path
+                .nodes
+                .iter()
+                .flat_map(|node| node.action)
+                .map(|action| action.cost)
+                .sum();
+            assert_eq!(actions, cheapest);
+            assert_eq!(path.nodes.first().unwrap().state, Grid::state(24));
+            assert_eq!(path.nodes.last().unwrap().state, Grid::state(0));
+        }
+    }
+
+    #[test]
+    fn heuristic_saves_expansions() {
+        let grid = Grid::new(&["1111111", "1111111", "1111111", "1111111"], 10, 13);
+        let dijkstra = Algorithm::Dijkstra.run(&grid, true);
+        let a_star = Algorithm::AStar.run(&grid, true);
+        let greedy = Algorithm::GreedyBestFirst.run(&grid, true);
+
+        assert_eq!(cost(&dijkstra), 3.0);
+        assert_eq!(cost(&a_star), 3.0);
+        assert_eq!(cost(&greedy), 3.0);
+        assert!(a_star.expanded < dijkstra.expanded);
+        assert_eq!(greedy.expanded, 3);
+    }
+
+    #[test]
+    fn weighted_a_star_stays_within_epsilon() {
+        let grid = maze();
+        for epsilon in [1.0, 1.5, 2.0, 5.0] {
+            let search = Algorithm::WeightedAStar(epsilon).run(&grid, false);
+            assert!(cost(&search) <= epsilon * 8.0);
+        }
+        assert!(cost(&Algorithm::GreedyBestFirst.run(&grid, true)) >= 8.0);
+    }
+
+    #[test]
+    fn unreachable_goals_give_no_path() {
+        let grid = Grid::new(&["110", "101", "011"], 0, 8);
+        for algorithm in [
+            Algorithm::AStar,
+            Algorithm::Dijkstra,
+            Algorithm::GreedyBestFirst,
+            Algorithm::WeightedAStar(2.0),
+            Algorithm::IdaStar,
+        ] {
+            assert!(algorithm.run(&grid, true).path.is_none());
+        }
+    }
+
+    #[test]
+    fn reopening_repairs_an_inconsistent_heuristic() {
+        //0 -> 1 -> 3 -> 4 costs 8 and 0 -> 2 -> 3 -> 4 costs 6. The heuristic never
+        //overestimates, but it drops by more than the cost of 2 -> 3, so 3 is expanded
+        //through 1 before the cheaper way through 2 is found.
+        let graph = Graph {
+            edges: vec![
+                (0, 1, 1.0),
+                (0, 2, 2.0),
+                (1, 3, 4.0),
+                (2, 3, 1.0),
+                (3, 4, 3.0),
+            ],
+            heuristic: vec![0.0, 0.0, 4.0, 0.0, 0.0],
+            goal: 4,
+        };
+
+        assert_eq!(cost(&Algorithm::AStar.run(&graph, false)), 8.0);
+        let reopened = Algorithm::AStar.run(&graph, true);
+        assert_eq!(cost(&reopened), 6.0);
+        let states: Vec<usize> = reopened
+            .path
+            .unwrap()
+            .nodes
+            .iter()
+            .map(|node| node.state.start.0)
+            .collect();
+        assert_eq!(states, vec![4, 3, 2, 0]);
+    }
+}
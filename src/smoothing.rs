@@ -0,0 +1,245 @@
+use las::{Bounds, Vector};
+
+use crate::a_star::{distance, Problem};
+use crate::model::NodeId;
+use crate::occupancy::clip;
+
+//Whether the segment stays out of every leaf a path may not pass through, the cells of the
+//start and goals aside. Touching such a leaf on a face, edge or corner does not count.
+pub fn line_of_sight(problem: &Problem, from: &Vector<f64>, to: &Vector<f64>) -> bool {
+    blocking(problem, from, to).is_none()
+}
+
+//Box of the first closed space found in the way of the segment. Space the tree has no cell
+//for is closed, and without an occupancy map nothing is known to be free, so a leaf that
+//holds points is closed as well.
+pub fn blocking(problem: &Problem, from: &Vector<f64>, to: &Vector<f64>) -> Option<Bounds> {
+    let tree = problem.tree;
+    let is_end = |id: NodeId| {
+        id == problem.start_state.start || problem.goals.iter().any(|goal| goal.start == id)
+    };
+    let is_clear = |id: NodeId| {
+        is_end(id)
+            || (problem.is_open(id)
+                && (problem.occupancy.is_some()
+                    || problem.ground.is_some()
+                    || tree[id].points.is_empty()))
+    };
+    let direction = [to.x - from.x, to.y - from.y, to.z - from.z];
+    let crosses = |bounds: &Bounds| clip(&shrink(bounds), from, &direction).is_some();
+
+    let mut stack = vec![tree.root];
+    while let Some(id) = stack.pop() {
+        let node = &tree[id];
+        if !crosses(&node.bounds) {
+            continue;
+        }
+        if node.is_leaf() {
+            if !is_clear(id) {
+                return Some(node.bounds);
+            }
+            continue;
+        }
+        for (octant, child) in node.children.iter().enumerate() {
+            match child {
+                Some(child) => stack.push(*child),
+                None if crosses(&node.octants[octant]) => return Some(node.octants[octant]),
+                None => {}
+            }
+        }
+    }
+    None
+}
+
+//Drops the waypoints that can be skipped, from each waypoint the route goes straight to the
+//farthest later one in line of sight. When not even the next waypoint is in sight, the route
+//itself cuts closed space and the box of that space is returned instead.
+pub fn smooth(problem: &Problem, waypoints: &[Vector<f64>]) -> Result<Vec<Vector<f64>>, Bounds> {
+    let Some(first) = waypoints.first() else {
+        return Ok(Vec::new());
+    };
+    let mut result = vec![*first];
+
+    let mut current = 0;
+    while current + 1 < waypoints.len() {
+        if let Some(blocked) = blocking(problem, &waypoints[current], &waypoints[current + 1]) {
+            return Err(blocked);
+        }
+        let next = (current + 2..waypoints.len())
+            .rev()
+            .find(|next| line_of_sight(problem, &waypoints[current], &waypoints[*next]))
+            .unwrap_or(current + 1);
+        result.push(waypoints[next]);
+        current = next;
+    }
+    Ok(result)
+}
+
+pub fn length(waypoints: &[Vector<f64>]) -> f64 {
+    waypoints
+        .windows(2)
+        .map(|pair| distance(&pair[0], &pair[1]))
+        .sum()
+}
+
+//The box pulled in a little on every side, so that a segment only clips it when it goes
+//inside.
+fn shrink(bounds: &Bounds) -> Bounds {
+    const MARGIN: f64 = 1e-9;
+    Bounds {
+        min: Vector {
+            x: bounds.min.x + MARGIN,
+            y: bounds.min.y + MARGIN,
+            z: bounds.min.z + MARGIN,
+        },
+        max: Vector {
+            x: bounds.max.x - MARGIN,
+            y: bounds.max.y - MARGIN,
+            z: bounds.max.z - MARGIN,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::a_star::{Mode, State};
+    use crate::occupancy::{OccupancyConfig, OccupancyMap};
+    use crate::test_util::{grid, point, unit_cells, vector, wall};
+
+    //Checks the segment at many points along it instead of clipping boxes.
+    fn sampled_clear(problem: &Problem, from: &Vector<f64>, to: &Vector<f64>) -> bool {
+        (0..=1000).all(|step| {
+            let t = step as f64 / 1000.0;
+            let at = vector(
+                from.x + (to.x - from.x) * t,
+                from.y + (to.y - from.y) * t,
+                from.z + (to.z - from.z) * t,
+            );
+            problem.tree.locate(at.x, at.y, at.z).is_none_or(|id| {
+                problem.is_open(id)
+                    || id == problem.start_state.start
+                    || problem.goals.iter().any(|goal| goal.start == id)
+            })
+        })
+    }
+
+    #[test]
+    fn sight_is_blocked_by_closed_cells_but_not_by_touching_them() {
+        let octree = grid([point(4.5, 4.5, 4.5)]);
+        let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+        let start = State::at(0.5, 0.5, 0.5, &octree).unwrap();
+        let problem = Problem::new(&octree, Mode::Cells, start, start).with_occupancy(&occupancy);
+
+        assert!(!line_of_sight(
+            &problem,
+            &vector(0.5, 0.5, 0.5),
+            &vector(7.5, 7.5, 7.5)
+        ));
+        assert!(!line_of_sight(
+            &problem,
+            &vector(4.5, 0.5, 4.5),
+            &vector(4.5, 7.5, 4.5)
+        ));
+        //along a face and through a corner of the occupied cell
+        assert!(line_of_sight(
+            &problem,
+            &vector(4.0, 0.5, 4.5),
+            &vector(4.0, 7.5, 4.5)
+        ));
+        assert!(line_of_sight(
+            &problem,
+            &vector(3.0, 3.0, 5.0),
+            &vector(5.0, 5.0, 3.0)
+        ));
+        assert!(line_of_sight(
+            &problem,
+            &vector(0.5, 7.5, 0.5),
+            &vector(7.5, 0.5, 7.5)
+        ));
+    }
+
+    #[test]
+    fn smoothing_goes_straight_where_nothing_is_in_the_way() {
+        let octree = grid([point(7.5, 7.5, 0.5)]);
+        let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+        let start = State::at(0.5, 0.5, 0.5, &octree).unwrap();
+        let goal = State::at(7.5, 0.5, 7.5, &octree).unwrap();
+        let mut problem =
+            Problem::new(&octree, Mode::Cells, start, goal).with_occupancy(&occupancy);
+        let path = problem.search().unwrap();
+
+        let (from, to) = (vector(0.2, 0.3, 0.4), vector(7.6, 0.3, 7.7));
+        let smoothed = smooth(&problem, &problem.waypoints(&path, from, to)).unwrap();
+        assert_eq!(smoothed.len(), 2);
+        assert!((length(&smoothed) - distance(&from, &to)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn smoothed_route_never_enters_the_wall() {
+        let octree = wall();
+        let occupancy = OccupancyMap::classify(&octree, &OccupancyConfig::default());
+        let (from, to) = (vector(0.5, 0.5, 0.5), vector(7.5, 0.5, 0.5));
+        let start = State::at(from.x, from.y, from.z, &octree).unwrap();
+        let goal = State::at(to.x, to.y, to.z, &octree).unwrap();
+        let mut problem =
+            Problem::new(&octree, Mode::Cells, start, goal).with_occupancy(&occupancy);
+        let path = problem.search().unwrap();
+        let waypoints = problem.waypoints(&path, from, to);
+
+        let smoothed = smooth(&problem, &waypoints).unwrap();
+        assert!(smoothed.len() < waypoints.len());
+        assert!(length(&smoothed) < length(&waypoints));
+        assert_eq!(smoothed.first(), waypoints.first());
+        assert_eq!(smoothed.last(), waypoints.last());
+        for pair in smoothed.windows(2) {
+            assert!(sampled_clear(&problem, &pair[0], &pair[1]));
+        }
+        //the only way through is the free cell in the corner of the wall
+        assert!(smoothed
+            .iter()
+            .any(|waypoint| waypoint.x == 4.5 && waypoint.y == 7.5 && waypoint.z == 7.5));
+    }
+
+    #[test]
+    fn without_occupancy_points_and_missing_cells_block_sight() {
+        let octree = wall();
+        let start = State::at(0.5, 0.5, 0.5, &octree).unwrap();
+        let goal = State::at(7.5, 0.5, 0.5, &octree).unwrap();
+        let mut problem = Problem::new(&octree, Mode::Cells, start, goal);
+
+        assert!(!line_of_sight(
+            &problem,
+            &vector(0.5, 0.5, 0.5),
+            &vector(7.5, 0.5, 0.5)
+        ));
+        assert!(line_of_sight(
+            &problem,
+            &vector(0.5, 7.5, 7.5),
+            &vector(7.5, 7.5, 7.5)
+        ));
+
+        //every cell is open without a map, so the path goes straight through the wall
+        let path = problem.search().unwrap();
+        let (from, to) = (vector(0.5, 0.5, 0.5), vector(7.5, 0.5, 0.5));
+        let blocked = smooth(&problem, &problem.waypoints(&path, from, to)).unwrap_err();
+        assert_eq!(blocked.min.x, 4.0);
+        assert_eq!(blocked.max.x, 5.0);
+
+        //cells only where the points are, the space between them was never created
+        let sparse = unit_cells(8, [point(0.5, 0.5, 0.5), point(7.5, 0.5, 0.5)]);
+        let start = State::at(0.5, 0.5, 0.5, &sparse).unwrap();
+        let goal = State::at(7.5, 0.5, 0.5, &sparse).unwrap();
+        let problem = Problem::new(&sparse, Mode::Cells, start, goal);
+        assert!(!line_of_sight(
+            &problem,
+            &vector(0.5, 0.5, 0.5),
+            &vector(7.5, 0.5, 0.5)
+        ));
+        assert!(line_of_sight(
+            &problem,
+            &vector(0.5, 0.5, 0.5),
+            &vector(0.9, 0.9, 0.9)
+        ));
+    }
+}
@@ -0,0 +1,73 @@
+//Fixtures shared by the tests of the modules.
+
+use las::{Bounds, Point, Vector};
+
+use crate::model::{Octree, OctreeConfig};
+
+pub(crate) fn point(x: f64, y: f64, z: f64) -> Point {
+    Point {
+        x,
+        y,
+        z,
+        ..Default::default()
+    }
+}
+
+pub(crate) fn vector(x: f64, y: f64, z: f64) -> Vector<f64> {
+    Vector { x, y, z }
+}
+
+pub(crate) fn cube(min: (f64, f64, f64), max: (f64, f64, f64)) -> Bounds {
+    Bounds {
+        min: vector(min.0, min.1, min.2),
+        max: vector(max.0, max.1, max.2),
+    }
+}
+
+//A cube from 0 to size split down to unit cells around the points, size a power of two.
+pub(crate) fn unit_cells<I: IntoIterator<Item = Point>>(size: u32, points: I) -> Octree {
+    let config = OctreeConfig {
+        max_depth: size.ilog2() as i32 + 1,
+        max_points_per_leaf: 0,
+        min_edge_length: 0.0,
+    };
+    let side = size as f64;
+    let mut octree = Octree::new(cube((0.0, 0.0, 0.0), (side, side, side)));
+    for point in points {
+        octree.insert_point(point, &config);
+    }
+    octree
+}
+
+//A 0..8 cube split down to unit cells around the points, empty octants filled.
+pub(crate) fn grid<I: IntoIterator<Item = Point>>(points: I) -> Octree {
+    let mut octree = unit_cells(8, points);
+    octree.fill_empty_octants();
+    octree
+}
+
+//A wall across x 4..5 with one free cell in the far corner.
+pub(crate) fn wall() -> Octree {
+    grid((0..63).map(|i| point(4.5, (i / 8) as f64 + 0.5, (i % 8) as f64 + 0.5)))
+}
+
+//Small xorshift generator so the property tests are repeatable without extra crates.
+pub(crate) struct Rng(pub u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    //One of the first n multiples of 1/4.
+    pub(crate) fn quarter(&mut self, n: u64) -> f64 {
+        self.below(n) as f64 / 4.0
+    }
+}